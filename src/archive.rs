@@ -0,0 +1,623 @@
+//! Extraction of compressed source archives.
+
+use bzip2::read::BzDecoder;
+use failure::Error;
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const BZIP2_MAGIC: &[u8] = &[0x42, 0x5a, 0x68];
+/// Magic present in the header of the first entry of uncompressed ustar and GNU tarballs.
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    Uncompressed,
+}
+
+impl Compression {
+    fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if header.starts_with(XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else if header.starts_with(BZIP2_MAGIC) {
+            Some(Compression::Bzip2)
+        } else if header
+            .get(TAR_MAGIC_OFFSET..)
+            .map(|magic| magic.starts_with(TAR_MAGIC))
+            .unwrap_or(false)
+        {
+            Some(Compression::Uncompressed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Options to customize how [`unpack_with_options`](fn.unpack_with_options.html) extracts an
+/// archive.
+#[derive(Clone)]
+pub struct UnpackOptions {
+    strip_prefix_components: usize,
+    allow_symlinks: bool,
+}
+
+impl UnpackOptions {
+    /// Create the default set of options, suitable to extract `.crate` files.
+    pub fn new() -> Self {
+        UnpackOptions {
+            strip_prefix_components: 1,
+            allow_symlinks: false,
+        }
+    }
+
+    /// Allow extracting symbolic and hard links. Links pointing outside the destination
+    /// directory (including absolute symbolic links) are still rejected, and so are entries
+    /// extracted through a symbolic link present earlier in the archive.
+    ///
+    /// By default archives containing links fail to extract, as `.crate` files never contain
+    /// them.
+    pub fn allow_symlinks(mut self, allow: bool) -> Self {
+        self.allow_symlinks = allow;
+        self
+    }
+
+    /// Set how many leading path components are discarded from each entry before extracting it.
+    /// Entries with fewer components than this are skipped.
+    ///
+    /// By default one component is discarded, which is the `name-version/` directory present in
+    /// `.crate` files.
+    pub fn strip_prefix_components(mut self, count: usize) -> Self {
+        self.strip_prefix_components = count;
+        self
+    }
+}
+
+/// Extract the tarball at `src` into the `dest` directory, discarding the first path component
+/// of each entry (the `name-version/` directory present in `.crate` files).
+///
+/// The compression format is detected from the magic bytes at the start of the file: gzip, zstd,
+/// xz and bzip2 compressed tarballs are supported, along with uncompressed ustar and GNU
+/// tarballs. An error is returned for any other format.
+pub fn unpack(src: &Path, dest: &Path) -> Result<(), Error> {
+    unpack_with_options(src, dest, &UnpackOptions::new())
+}
+
+/// Extract the tarball at `src` into the `dest` directory, customizing the extraction with the
+/// provided [`UnpackOptions`](struct.UnpackOptions.html).
+///
+/// The compression format is detected the same way as [`unpack`](fn.unpack.html).
+pub fn unpack_with_options(src: &Path, dest: &Path, options: &UnpackOptions) -> Result<(), Error> {
+    unpack_with_progress(src, dest, options, &mut |_, _| {})
+}
+
+/// Extract the tarball at `src` into the `dest` directory like
+/// [`unpack_with_options`](fn.unpack_with_options.html), calling `progress` after each entry is
+/// extracted with the number of entries and the number of bytes extracted so far.
+pub fn unpack_with_progress(
+    src: &Path,
+    dest: &Path,
+    options: &UnpackOptions,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let compression = {
+        let header = reader.fill_buf()?;
+        match Compression::detect(header) {
+            Some(compression) => compression,
+            None => failure::bail!("unknown compression format for archive {}", src.display()),
+        }
+    };
+
+    match compression {
+        Compression::Gzip => unpack_entries(
+            &mut Archive::new(GzDecoder::new(reader)),
+            dest,
+            options,
+            progress,
+        ),
+        Compression::Zstd => unpack_entries(
+            &mut Archive::new(zstd::Decoder::new(reader)?),
+            dest,
+            options,
+            progress,
+        ),
+        Compression::Xz => unpack_entries(
+            &mut Archive::new(XzDecoder::new(reader)),
+            dest,
+            options,
+            progress,
+        ),
+        Compression::Uncompressed => {
+            unpack_entries(&mut Archive::new(reader), dest, options, progress)
+        }
+        Compression::Bzip2 => unpack_entries(
+            &mut Archive::new(BzDecoder::new(reader)),
+            dest,
+            options,
+            progress,
+        ),
+    }
+}
+
+fn unpack_entries<R: Read>(
+    archive: &mut Archive<R>,
+    path: &Path,
+    options: &UnpackOptions,
+    progress: &mut dyn FnMut(u64, u64),
+) -> Result<(), Error> {
+    std::fs::create_dir_all(path)?;
+
+    let mut extracted_entries = 0;
+    let mut extracted_bytes = 0;
+    let entries = archive.entries()?;
+    for entry in entries {
+        let mut entry = entry?;
+        let relpath = {
+            let path = entry.path();
+            let path = path?;
+            path.into_owned()
+        };
+        // Absolute paths must be rejected before stripping the leading components, otherwise the
+        // root would be the component thrown away.
+        if relpath.has_root() {
+            failure::bail!("archive entry {} has an absolute path", relpath.display());
+        }
+        let mut components = relpath.components();
+        // Throw away the leading path components
+        for _ in 0..options.strip_prefix_components {
+            components.next();
+        }
+        if components.as_path().as_os_str().is_empty() {
+            continue;
+        }
+        extracted_entries += 1;
+        extracted_bytes += entry.size();
+        let full_path = join_inside(path, components.as_path())?;
+        // Writing through a symbolic link extracted earlier could escape the destination, even if
+        // the path is inside of it.
+        ensure_no_symlinks(path, &full_path, &relpath)?;
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let entry_type = entry.header().entry_type();
+        if entry_type == EntryType::Symlink || entry_type == EntryType::Link {
+            if !options.allow_symlinks {
+                failure::bail!("archive entry {} is a link", relpath.display());
+            }
+            let target = match entry.link_name()? {
+                Some(target) => target.into_owned(),
+                None => failure::bail!("archive entry {} has no link target", relpath.display()),
+            };
+
+            if entry_type == EntryType::Symlink {
+                // Symbolic links are relative to the directory containing them.
+                let parent = components
+                    .as_path()
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""));
+                join_inside(path, &parent.join(&target))?;
+                entry.unpack(&full_path)?;
+            } else {
+                // Hard links are relative to the root of the archive, and the tar crate would
+                // resolve them relative to the current directory.
+                if target.has_root() {
+                    failure::bail!(
+                        "archive entry {} links to an absolute path",
+                        relpath.display()
+                    );
+                }
+                let mut target_components = target.components();
+                for _ in 0..options.strip_prefix_components {
+                    target_components.next();
+                }
+                let target = join_inside(path, target_components.as_path())?;
+                ensure_no_symlinks(path, &target, &relpath)?;
+                std::fs::hard_link(target, &full_path)?;
+            }
+            progress(extracted_entries, extracted_bytes);
+            continue;
+        }
+
+        entry.unpack(&full_path)?;
+
+        // Explicitly apply the stored mode, so that scripts and binaries vendored in the crate
+        // stay executable regardless of the tar crate's defaults. Read-only directories would
+        // prevent extracting their content and removing it afterwards, so their owner is always
+        // allowed to write in them.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = entry.header().mode()? & 0o777;
+            if entry_type.is_file() {
+                std::fs::set_permissions(&full_path, std::fs::Permissions::from_mode(mode))?;
+            } else if entry_type.is_dir() {
+                std::fs::set_permissions(
+                    &full_path,
+                    std::fs::Permissions::from_mode(mode | 0o700),
+                )?;
+            }
+        }
+
+        progress(extracted_entries, extracted_bytes);
+    }
+
+    Ok(())
+}
+
+/// Join `relative` onto `base`, returning an error if the resulting path would be outside `base`.
+///
+/// The check is done lexically, without touching the filesystem, as the destination directory
+/// might not exist yet.
+fn join_inside(base: &Path, relative: &Path) -> Result<PathBuf, Error> {
+    let mut normalized = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    failure::bail!(
+                        "archive entry {} points outside the destination directory",
+                        relative.display()
+                    );
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => failure::bail!(
+                "archive entry {} points outside the destination directory",
+                relative.display()
+            ),
+        }
+    }
+    Ok(base.join(normalized))
+}
+
+/// Ensure none of the components of `full_path` (which must be inside `base`) is an existing
+/// symbolic link.
+fn ensure_no_symlinks(base: &Path, full_path: &Path, entry: &Path) -> Result<(), Error> {
+    let mut current = base.to_path_buf();
+    for component in full_path.strip_prefix(base)?.components() {
+        current.push(component);
+        if let Ok(meta) = std::fs::symlink_metadata(&current) {
+            if meta.file_type().is_symlink() {
+                failure::bail!(
+                    "archive entry {} goes through the symbolic link {}",
+                    entry.display(),
+                    current.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+    use std::io::Write;
+
+    fn crate_tarball() -> Result<Vec<u8>, Error> {
+        tarball("foo-1.0.0/")
+    }
+
+    fn tarball(prefix: &str) -> Result<Vec<u8>, Error> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in &[
+            ("Cargo.toml", &b"[package]\nname = \"foo\"\n"[..]),
+            ("src/lib.rs", &b"pub fn foo() {}\n"[..]),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, format!("{}{}", prefix, path), *content)?;
+        }
+        Ok(builder.into_inner()?)
+    }
+
+    fn assert_round_trip(compressed: &[u8]) -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        let dest = tmp.path().join("dest");
+        std::fs::write(&src, compressed)?;
+
+        super::unpack(&src, &dest)?;
+
+        assert_eq!(
+            std::fs::read(dest.join("Cargo.toml"))?,
+            b"[package]\nname = \"foo\"\n"
+        );
+        assert_eq!(
+            std::fs::read(dest.join("src").join("lib.rs"))?,
+            b"pub fn foo() {}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_gzip() -> Result<(), Error> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&crate_tarball()?)?;
+        assert_round_trip(&encoder.finish()?)
+    }
+
+    #[test]
+    fn test_unpack_zstd() -> Result<(), Error> {
+        assert_round_trip(&zstd::encode_all(&crate_tarball()?[..], 0)?)
+    }
+
+    #[test]
+    fn test_unpack_xz() -> Result<(), Error> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(&crate_tarball()?)?;
+        assert_round_trip(&encoder.finish()?)
+    }
+
+    #[test]
+    fn test_unpack_with_progress() -> Result<(), Error> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&crate_tarball()?)?;
+
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        std::fs::write(&src, encoder.finish()?)?;
+
+        let mut reported = Vec::new();
+        super::unpack_with_progress(
+            &src,
+            &tmp.path().join("dest"),
+            &super::UnpackOptions::new(),
+            &mut |entries, bytes| reported.push((entries, bytes)),
+        )?;
+        assert_eq!(reported, vec![(1, 23), (2, 39)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_uncompressed() -> Result<(), Error> {
+        assert_round_trip(&crate_tarball()?)
+    }
+
+    #[test]
+    fn test_unpack_bzip2() -> Result<(), Error> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+        encoder.write_all(&crate_tarball()?)?;
+        assert_round_trip(&encoder.finish()?)
+    }
+
+    #[test]
+    fn test_unpack_unknown_format() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        std::fs::write(&src, b"definitely not an archive")?;
+
+        assert!(super::unpack(&src, &tmp.path().join("dest")).is_err());
+        assert!(!tmp.path().join("dest").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_strip_prefix_components() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo.tar.gz");
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tarball("")?)?;
+        std::fs::write(&src, encoder.finish()?)?;
+
+        // Without stripping any component the whole tree is extracted
+        let dest = tmp.path().join("no-strip");
+        super::unpack_with_options(
+            &src,
+            &dest,
+            &super::UnpackOptions::new().strip_prefix_components(0),
+        )?;
+        assert!(dest.join("Cargo.toml").is_file());
+        assert!(dest.join("src").join("lib.rs").is_file());
+
+        // Entries shorter than the stripped prefix are skipped
+        let dest = tmp.path().join("strip");
+        super::unpack(&src, &dest)?;
+        assert!(!dest.join("Cargo.toml").exists());
+        assert!(dest.join("lib.rs").is_file());
+
+        Ok(())
+    }
+
+    fn link_tarball(entry_type: tar::EntryType, target: &str) -> Result<Vec<u8>, Error> {
+        let content = b"pub fn foo() {}\n";
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "foo-1.0.0/src/lib.rs", &content[..])?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_link_name(target)?;
+        header.set_cksum();
+        builder.append_data(&mut header, "foo-1.0.0/src/link.rs", &[][..])?;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&builder.into_inner()?)?;
+        Ok(encoder.finish()?)
+    }
+
+    #[test]
+    fn test_unpack_symlink_chain() -> Result<(), Error> {
+        // Each link looks like it's inside the destination on its own, but `d/e` resolves to the
+        // parent of the destination once `d` is extracted.
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, target) in &[("foo-1.0.0/d", "."), ("foo-1.0.0/d/e", "..")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            header.set_link_name(target)?;
+            header.set_cksum();
+            builder.append_data(&mut header, name, &[][..])?;
+        }
+        let content = b"malicious";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "foo-1.0.0/d/e/evil", &content[..])?;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&builder.into_inner()?)?;
+
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        std::fs::write(&src, encoder.finish()?)?;
+
+        let allow = super::UnpackOptions::new().allow_symlinks(true);
+        let err = super::unpack_with_options(&src, &tmp.path().join("dest"), &allow).unwrap_err();
+        assert!(err.to_string().contains("goes through the symbolic link"));
+        assert!(!tmp.path().join("evil").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_symlinks() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        let allow = super::UnpackOptions::new().allow_symlinks(true);
+
+        // Malicious absolute symlinks are always rejected
+        std::fs::write(&src, link_tarball(tar::EntryType::Symlink, "/etc/passwd")?)?;
+        assert!(super::unpack(&src, &tmp.path().join("default")).is_err());
+        assert!(super::unpack_with_options(&src, &tmp.path().join("absolute"), &allow).is_err());
+
+        // Relative symlinks escaping the destination are rejected
+        std::fs::write(&src, link_tarball(tar::EntryType::Symlink, "../../secret")?)?;
+        assert!(super::unpack_with_options(&src, &tmp.path().join("escape"), &allow).is_err());
+
+        // Links inside the destination are only extracted when they're allowed
+        std::fs::write(&src, link_tarball(tar::EntryType::Symlink, "lib.rs")?)?;
+        assert!(super::unpack(&src, &tmp.path().join("disallowed")).is_err());
+        let dest = tmp.path().join("symlink");
+        super::unpack_with_options(&src, &dest, &allow)?;
+        assert_eq!(
+            std::fs::read(dest.join("src").join("link.rs"))?,
+            b"pub fn foo() {}\n"
+        );
+
+        std::fs::write(
+            &src,
+            link_tarball(tar::EntryType::Link, "foo-1.0.0/src/lib.rs")?,
+        )?;
+        let dest = tmp.path().join("hardlink");
+        super::unpack_with_options(&src, &dest, &allow)?;
+        assert_eq!(
+            std::fs::read(dest.join("src").join("link.rs"))?,
+            b"pub fn foo() {}\n"
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unpack_executable() -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o555);
+        header.set_cksum();
+        builder.append_data(&mut header, "foo-1.0.0/scripts/", &[][..])?;
+        for (path, mode) in &[
+            ("foo-1.0.0/build.sh", 0o755),
+            ("foo-1.0.0/README", 0o644),
+            ("foo-1.0.0/scripts/run.sh", 0o755),
+        ] {
+            let content = b"#!/bin/sh\n";
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            builder.append_data(&mut header, path, &content[..])?;
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&builder.into_inner()?)?;
+
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        let dest = tmp.path().join("dest");
+        std::fs::write(&src, encoder.finish()?)?;
+        super::unpack(&src, &dest)?;
+
+        let mode = |name| -> Result<u32, Error> {
+            Ok(std::fs::metadata(dest.join(name))?.permissions().mode() & 0o777)
+        };
+        assert_eq!(mode("build.sh")?, 0o755);
+        assert_eq!(mode("README")?, 0o644);
+        assert_eq!(mode("scripts/run.sh")?, 0o755);
+        // Read-only directories are writable by their owner after the extraction.
+        assert_eq!(mode("scripts")?, 0o755);
+        Ok(())
+    }
+
+    fn raw_name_tarball(name: &[u8]) -> Result<Vec<u8>, Error> {
+        // tar::Builder refuses to add paths containing `..` or a root, so the header is built
+        // manually.
+        let content = b"malicious";
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &content[..])?;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&builder.into_inner()?)?;
+        Ok(encoder.finish()?)
+    }
+
+    #[test]
+    fn test_unpack_path_traversal() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("foo-1.0.0.crate");
+        std::fs::write(&src, raw_name_tarball(b"foo-1.0.0/../escaped")?)?;
+
+        assert!(super::unpack(&src, &tmp.path().join("dest")).is_err());
+        assert!(!tmp.path().join("escaped").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_absolute_path() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let dest = tmp.path().join("dest");
+        let name = format!("{}/escaped", tmp.path().display());
+        let src = tmp.path().join("foo-1.0.0.crate");
+        std::fs::write(&src, raw_name_tarball(name.as_bytes())?)?;
+
+        let err = super::unpack(&src, &dest).unwrap_err();
+        assert!(err.to_string().contains("has an absolute path"));
+        assert!(!tmp.path().join("escaped").exists());
+        assert!(!dest.join(tmp.path().strip_prefix("/")?).exists());
+        Ok(())
+    }
+}
@@ -1,16 +1,69 @@
-use crate::cmd::{Command, MountKind, Runnable, SandboxBuilder};
-use crate::prepare::Prepare;
-use crate::{Crate, Toolchain, Workspace};
+use crate::cmd::{Binary, Command, MountKind, ProcessLinesActions, Runnable, SandboxBuilder};
+use crate::prepare::{Prepare, PrepareOptions};
+use crate::snapshot::SourceSnapshot;
+use crate::{
+    CargoFailure, CargoMessage, CompilerMessage, Crate, PackageMetadata, Toolchain, Workspace,
+};
 use failure::Error;
 use remove_dir_all::remove_dir_all;
-use std::path::PathBuf;
-use std::vec::Vec;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub(crate) struct CratePatch {
     pub(crate) name: String,
-    pub(crate) uri: String,
-    pub(crate) branch: String,
+    /// Registry whose crate is replaced by the patch, or `None` for crates.io.
+    pub(crate) registry: Option<String>,
+    pub(crate) patch: Patch,
+}
+
+/// Replacement of a crate added to a build with
+/// [`BuildBuilder::patch`](struct.BuildBuilder.html#method.patch).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Patch {
+    /// Replace the crate with the one in a branch of a git repository.
+    Git {
+        /// URL of the git repository.
+        url: String,
+        /// Branch of the repository containing the crate.
+        branch: String,
+    },
+    /// Replace the crate with the one located in a directory of the host machine.
+    ///
+    /// The directory is mounted read-only inside the sandbox at the same path it has on the host,
+    /// so it must be an absolute path.
+    Path(PathBuf),
+    /// Replace the crate with a version published on crates.io.
+    ///
+    /// Cargo doesn't allow patching crates.io with itself, so this can only be used with
+    /// [`BuildBuilder::patch_registry`](struct.BuildBuilder.html#method.patch_registry).
+    CratesIo {
+        /// Version requirement of the crate.
+        version: String,
+    },
+    #[doc(hidden)]
+    __NonExaustive,
+}
+
+#[derive(Clone)]
+pub(crate) struct CrateDependency {
+    pub(crate) name: String,
+    pub(crate) req: String,
+    pub(crate) kind: DependencyKind,
+}
+
+/// Kind of a dependency added to a build with
+/// [`BuildBuilder::add_dependency`](struct.BuildBuilder.html#method.add_dependency).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencyKind {
+    /// Dependency added to the `[dependencies]` table.
+    Normal,
+    /// Dependency added to the `[dev-dependencies]` table.
+    Dev,
+    /// Dependency added to the `[build-dependencies]` table.
+    Build,
+    #[doc(hidden)]
+    __NonExaustive,
 }
 
 /// Directory in the [`Workspace`](struct.Workspace.html) where builds can be executed.
@@ -29,12 +82,103 @@ pub struct BuildBuilder<'a> {
     toolchain: &'a Toolchain,
     krate: &'a Crate,
     sandbox: SandboxBuilder,
-    patches: Vec<CratePatch>,
+    settings: BuildSettings,
+    options: PrepareOptions,
+}
+
+/// Settings of a build applied to the commands executed in the sandbox.
+#[derive(Default)]
+pub(crate) struct BuildSettings {
+    env: Vec<(&'static str, String)>,
+    target_dir: Option<PathBuf>,
+    profile_args: Vec<String>,
+    rustc_wrapper: Option<PathBuf>,
+    sccache_stats: bool,
 }
 
+/// Cargo subcommands accepting the `--release` and `--profile` flags.
+const PROFILE_SUBCOMMANDS: &[&str] = &[
+    "build", "check", "clippy", "doc", "fix", "run", "rustc", "rustdoc", "test",
+];
+
+/// File in a shared target directory recording the crate it belongs to.
+const TARGET_DIR_CRATE_FILE: &str = ".rustwide-crate";
+
 impl<'a> BuildBuilder<'a> {
-    /// Add a patch to this build.
-    /// Patches get added to the crate's Cargo.toml in the `patch.crates-io` table.
+    /// Add a patch to this build, replacing the crate with the provided source. Patches get
+    /// added to the crate's Cargo.toml in the `patch.crates-io` table.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, Patch, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .patch("bar", Patch::Path("/home/foo/bar".into()))
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn patch(mut self, name: &str, patch: Patch) -> Self {
+        self.options.patches.push(CratePatch {
+            name: name.into(),
+            registry: None,
+            patch,
+        });
+        self
+    }
+
+    /// Add a patch to this build, replacing a crate of a registry other than crates.io with the
+    /// provided source. Patches get added to the crate's Cargo.toml in the `patch."<registry>"`
+    /// table, where the registry is either the name of a registry configured in cargo or the URL
+    /// of its index.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, Patch, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .patch_registry(
+    ///         "https://git.example.com/index",
+    ///         "bar",
+    ///         Patch::CratesIo { version: "1.0".into() },
+    ///     )
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn patch_registry(mut self, registry: &str, name: &str, patch: Patch) -> Self {
+        self.options.patches.push(CratePatch {
+            name: name.into(),
+            registry: Some(registry.into()),
+            patch,
+        });
+        self
+    }
+
+    /// Add a patch to this build, replacing the crate with the one in a branch of a git
+    /// repository. This is a shorthand for [`patch`](#method.patch) with
+    /// [`Patch::Git`](enum.Patch.html#variant.Git).
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -54,15 +198,203 @@ impl<'a> BuildBuilder<'a> {
     ///     })?;
     /// # Ok(())
     /// # }
-    pub fn patch_with_git(mut self, name: &str, uri: &str, branch: &str) -> Self {
-        self.patches.push(CratePatch {
+    /// ```
+    pub fn patch_with_git(self, name: &str, uri: &str, branch: &str) -> Self {
+        self.patch(
+            name,
+            Patch::Git {
+                url: uri.into(),
+                branch: branch.into(),
+            },
+        )
+    }
+
+    /// Add a patch to this build, replacing the crate with the one located in the provided
+    /// directory of the host machine. This is a shorthand for [`patch`](#method.patch) with
+    /// [`Patch::Path`](enum.Patch.html#variant.Path).
+    ///
+    /// The directory is mounted read-only inside the sandbox at the same path it has on the host,
+    /// so it must be an absolute path.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # use std::path::Path;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .patch_with_path("bar", Path::new("/home/foo/bar"))
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn patch_with_path(self, name: &str, path: &Path) -> Self {
+        self.patch(name, Patch::Path(path.into()))
+    }
+
+    /// Add a dependency from crates.io to the crate being built, matching the provided version
+    /// requirement. The dependency is added to the copy of `Cargo.toml` in the build directory,
+    /// so the original source of the crate is not changed.
+    ///
+    /// If the crate already declares a dependency with the same name and kind, it's replaced by
+    /// the new one.
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, DependencyKind, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox)
+    ///     .add_dependency("proptest", "0.9", DependencyKind::Dev)
+    ///     .run(|build| {
+    ///         build.cargo().args(&["test", "--all"]).run()?;
+    ///         Ok(())
+    ///     })?;
+    /// # Ok(())
+    /// # }
+    pub fn add_dependency(mut self, name: &str, req: &str, kind: DependencyKind) -> Self {
+        self.options.dependencies.push(CrateDependency {
             name: name.into(),
-            uri: uri.into(),
-            branch: branch.into(),
+            req: req.into(),
+            kind,
         });
         self
     }
 
+    /// Regenerate the crate's `Cargo.lock` before the build, updating all the dependencies to
+    /// their latest compatible versions, even if the crate already has a lockfile.
+    ///
+    /// By default the existing lockfile is used, and it's only regenerated if it's missing or
+    /// outdated.
+    pub fn update_lockfile(mut self, update: bool) -> Self {
+        self.options.update_lockfile = update;
+        self
+    }
+
+    /// Require the crate to have an up to date `Cargo.lock`, returning
+    /// [`PrepareError::OutdatedLockfile`](enum.PrepareError.html#variant.OutdatedLockfile)
+    /// instead of generating a new lockfile if it's missing or outdated.
+    ///
+    /// The lockfile is checked against the crate's own `Cargo.toml`: if patches or dependencies
+    /// are added to the build, the entries they affect are then updated without changing the other
+    /// locked versions.
+    ///
+    /// By default missing and outdated lockfiles are regenerated.
+    pub fn require_lockfile(mut self, require: bool) -> Self {
+        self.options.require_lockfile = require;
+        self
+    }
+
+    /// Call the provided function while the crate's source is being extracted in the build
+    /// directory, with the number of files and the number of bytes extracted so far. This allows
+    /// showing the progress of extracting large crates.
+    ///
+    /// Progress is currently reported just for crates.io crates, as the source of the other crate
+    /// types is copied instead of being extracted.
+    pub fn on_extract_progress<F: Fn(u64, u64) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.options.extract_progress = Some(Arc::new(f));
+        self
+    }
+
+    /// Copy the crate's source over the existing contents of the source directory, instead of
+    /// removing them first. This allows overlaying the crate onto a directory seeded in advance
+    /// (for example with vendored dependencies), which can be found with
+    /// [`BuildDirectory::host_source_dir`](struct.BuildDirectory.html#method.host_source_dir).
+    ///
+    /// Files with the same path as a file of the crate are replaced, while the other ones are
+    /// kept: the caller is responsible for removing stale files left by previous builds. The
+    /// source directory is still removed once the build finishes.
+    pub fn keep_existing_source(mut self, keep: bool) -> Self {
+        self.options.keep_existing_source = keep;
+        self
+    }
+
+    /// Wrap all the `rustc` invocations of the build with the provided binary, through the
+    /// `RUSTC_WRAPPER` environment variable. The binary is mounted read-only inside the sandbox,
+    /// so it must be able to run inside the sandbox image (for example by being statically
+    /// linked). Running the build fails if the path doesn't point to a file.
+    pub fn rustc_wrapper(mut self, path: &Path) -> Self {
+        self.settings.rustc_wrapper = Some(path.into());
+        self
+    }
+
+    /// Cache the compilation results of the build with [sccache](https://github.com/mozilla/sccache),
+    /// storing them in the provided directory on the host. The sccache binary is used as the
+    /// [`rustc_wrapper`](#method.rustc_wrapper), so it must be able to run inside the sandbox
+    /// image (for example by being statically linked).
+    ///
+    /// The cache directory can be shared between builds, but the sccache server doesn't outlive
+    /// the sandboxed commands, so its statistics only cover a single command: they can be printed
+    /// after each command with [`sccache_stats`](#method.sccache_stats).
+    pub fn sccache(self, sccache: &Path, cache_dir: &Path) -> Self {
+        let mut builder = self.rustc_wrapper(sccache);
+        let sandbox_dir = &*crate::cmd::container_dirs::SCCACHE_DIR;
+        builder.sandbox =
+            builder
+                .sandbox
+                .mount_internal(cache_dir, sandbox_dir, MountKind::ReadWrite);
+        builder
+            .settings
+            .env
+            .push(("SCCACHE_DIR", sandbox_dir.to_string_lossy().into()));
+        builder
+    }
+
+    /// Print the statistics of sccache after each `cargo` command of the build, configured with
+    /// [`sccache`](#method.sccache). The statistics are part of the output of the command, so they
+    /// are logged and captured with the rest of it.
+    ///
+    /// Running the build fails if sccache is not configured. This requires a `sh` shell in the
+    /// sandbox image, as cargo and sccache have to run in the same sandbox: the sccache server
+    /// doesn't outlive the command.
+    pub fn sccache_stats(mut self, show: bool) -> Self {
+        self.settings.sccache_stats = show;
+        self
+    }
+
+    /// Use the provided directory on the host as the build's target directory, instead of the one
+    /// inside the build directory. The directory is mounted in the sandbox and used by all the
+    /// cargo invocations of the build.
+    ///
+    /// Sharing a target directory between builds avoids compiling the same dependencies again,
+    /// but the artifacts of a build are visible to all the other builds using the directory: it
+    /// should only be shared between builds of the same crate, and running a build of another
+    /// crate with it returns an error. The directory is not removed by
+    /// [`BuildDirectory::purge`](struct.BuildDirectory.html#method.purge).
+    pub fn target_dir(mut self, path: &Path) -> Self {
+        self.settings.target_dir = Some(path.into());
+        self
+    }
+
+    /// Compile the crate with the provided cargo profile, passing `--release` (for `release`) or
+    /// `--profile <name>` to the cargo subcommands accepting it, like `build`, `check` and
+    /// `test`, when they're executed with [`Build::cargo`](struct.Build.html#method.cargo).
+    /// Choosing the `dev` profile doesn't pass any flag, as it's the default one.
+    ///
+    /// Custom profiles require cargo 1.57 or later.
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.settings.profile_args = match profile {
+            "dev" => Vec::new(),
+            "release" => vec!["--release".into()],
+            other => vec!["--profile".into(), other.into()],
+        };
+        self
+    }
+
     /// Run a sandboxed build of the provided crate with the provided toolchain. The closure will
     /// be provided an instance of [`Build`](struct.Build.html) that allows spawning new processes
     /// inside the sandbox.
@@ -87,8 +419,42 @@ impl<'a> BuildBuilder<'a> {
     /// # Ok(())
     /// # }
     pub fn run<R, F: FnOnce(&Build) -> Result<R, Error>>(self, f: F) -> Result<R, Error> {
+        self.build_dir.run(
+            self.toolchain,
+            self.krate,
+            self.sandbox,
+            self.settings,
+            self.options,
+            f,
+        )
+    }
+
+    /// Prepare the build without running it: the crate's source is copied in the build
+    /// directory, its manifest is validated and its dependencies are resolved and fetched, but
+    /// nothing is compiled. The source is removed from the build directory afterwards.
+    ///
+    /// This is a cheap way to check whether a crate can be built at all, as the errors returned
+    /// are the same ones [`run`](#method.run) would return before the build starts, like
+    /// [`PrepareError`](enum.PrepareError.html)s.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox).prepare_only()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prepare_only(self) -> Result<(), Error> {
         self.build_dir
-            .run(self.toolchain, self.krate, self.sandbox, self.patches, f)
+            .prepare_only(self.toolchain, self.krate, self.options)
     }
 }
 
@@ -132,7 +498,8 @@ impl BuildDirectory {
             toolchain,
             krate,
             sandbox,
-            patches: Vec::new(),
+            settings: BuildSettings::default(),
+            options: PrepareOptions::default(),
         }
     }
 
@@ -140,29 +507,83 @@ impl BuildDirectory {
         &mut self,
         toolchain: &Toolchain,
         krate: &Crate,
-        sandbox: SandboxBuilder,
-        patches: Vec<CratePatch>,
+        mut sandbox: SandboxBuilder,
+        mut settings: BuildSettings,
+        options: PrepareOptions,
         f: F,
     ) -> Result<R, Error> {
+        if settings.sccache_stats && settings.rustc_wrapper.is_none() {
+            failure::bail!("showing the sccache statistics requires sccache to be configured");
+        }
+        if let Some(wrapper) = &settings.rustc_wrapper {
+            let sandbox_path = rustc_wrapper_sandbox_path(wrapper)?;
+            sandbox = sandbox.mount_internal(wrapper, &sandbox_path, MountKind::ReadOnly);
+            settings
+                .env
+                .push(("RUSTC_WRAPPER", sandbox_path.to_string_lossy().into()));
+        }
+
         let source_dir = self.source_dir();
-        if source_dir.exists() {
+        if source_dir.exists() && !options.keep_existing_source {
             remove_dir_all(&source_dir)?;
         }
 
-        let mut prepare = Prepare::new(&self.workspace, toolchain, krate, &source_dir, patches);
+        let sandbox = options
+            .patches
+            .iter()
+            .fold(sandbox, |sandbox, patch| match &patch.patch {
+                Patch::Path(path) => sandbox.mount(path, path, MountKind::ReadOnly),
+                _ => sandbox,
+            });
+
+        let mut prepare = Prepare::new(&self.workspace, toolchain, krate, &source_dir, options);
         prepare.prepare()?;
 
-        std::fs::create_dir_all(self.target_dir())?;
+        let target_dir = match settings.target_dir {
+            Some(target_dir) => {
+                std::fs::create_dir_all(&target_dir)?;
+                check_target_dir_crate(&target_dir, krate)?;
+                target_dir
+            }
+            None => {
+                let target_dir = self.target_dir();
+                std::fs::create_dir_all(&target_dir)?;
+                target_dir
+            }
+        };
         let res = f(&Build {
             dir: self,
             toolchain,
             sandbox: sandbox.clone(),
+            env: settings.env,
+            target_dir,
+            profile_args: settings.profile_args,
+            sccache_stats: settings.sccache_stats,
         })?;
 
         remove_dir_all(&source_dir)?;
         Ok(res)
     }
 
+    fn prepare_only(
+        &mut self,
+        toolchain: &Toolchain,
+        krate: &Crate,
+        options: PrepareOptions,
+    ) -> Result<(), Error> {
+        let source_dir = self.source_dir();
+        if source_dir.exists() && !options.keep_existing_source {
+            remove_dir_all(&source_dir)?;
+        }
+
+        let res = Prepare::new(&self.workspace, toolchain, krate, &source_dir, options).prepare();
+
+        if source_dir.exists() {
+            remove_dir_all(&source_dir)?;
+        }
+        res
+    }
+
     /// Remove all the contents of the build directory, freeing disk space.
     pub fn purge(&mut self) -> Result<(), Error> {
         let build_dir = self.build_dir();
@@ -172,6 +593,13 @@ impl BuildDirectory {
         Ok(())
     }
 
+    /// Get the path on the host machine where the crate's source is copied during builds. The
+    /// directory only exists while a build is running, unless it's seeded in advance for
+    /// [`BuildBuilder::keep_existing_source`](struct.BuildBuilder.html#method.keep_existing_source).
+    pub fn host_source_dir(&self) -> PathBuf {
+        self.source_dir()
+    }
+
     fn build_dir(&self) -> PathBuf {
         self.workspace.builds_dir().join(&self.name)
     }
@@ -185,6 +613,71 @@ impl BuildDirectory {
     }
 }
 
+/// Shell script running the command passed as its arguments and then printing the statistics of
+/// the sccache server, which is shut down when the sandbox exits. The exit status of the command
+/// is preserved.
+const SCCACHE_STATS_SCRIPT: &str =
+    r#""$@"; status=$?; "$RUSTC_WRAPPER" --show-stats; exit $status"#;
+
+/// Runs cargo inside the sandbox through a shell printing the sccache statistics once it exits.
+struct CargoWithSccacheStats<'a> {
+    toolchain: &'a Toolchain,
+}
+
+impl Runnable for CargoWithSccacheStats<'_> {
+    fn name(&self) -> Binary {
+        Binary::Global("sh".into())
+    }
+
+    fn prepare_command<'w, 'pl>(&self, cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
+        let cargo = crate::cmd::container_dirs::CARGO_BIN_DIR.join("cargo");
+        let cmd = cmd.args(&["-c", SCCACHE_STATS_SCRIPT, "sh"]).args(&[cargo]);
+        self.toolchain.cargo().prepare_command(cmd)
+    }
+}
+
+/// Return where the rustc wrapper is mounted inside the sandbox.
+fn rustc_wrapper_sandbox_path(wrapper: &Path) -> Result<PathBuf, Error> {
+    match wrapper.file_name() {
+        Some(name) if wrapper.is_file() => Ok(crate::cmd::container_dirs::TOOLS_DIR.join(name)),
+        _ => failure::bail!("the rustc wrapper {} is not a file", wrapper.display()),
+    }
+}
+
+/// Ensure a shared target directory is only used by the builds of a single crate, recording the
+/// crate in it the first time it's used.
+fn check_target_dir_crate(target_dir: &Path, krate: &Crate) -> Result<(), Error> {
+    let file = target_dir.join(TARGET_DIR_CRATE_FILE);
+    let krate = krate.to_string();
+
+    // The lock prevents concurrent builds of different crates from both recording themselves.
+    let mut lock = file.clone().into_os_string();
+    lock.push(".lock");
+    crate::utils::file_lock(
+        Path::new(&lock),
+        "check the crate using the target directory",
+        || {
+            match std::fs::read_to_string(&file) {
+                Ok(existing) => {
+                    if existing != krate {
+                        failure::bail!(
+                            "the target directory {} is used by the builds of {}, not {}",
+                            target_dir.display(),
+                            existing,
+                            krate
+                        );
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    std::fs::write(&file, &krate)?
+                }
+                Err(err) => return Err(err.into()),
+            }
+            Ok(())
+        },
+    )
+}
+
 /// API to interact with a running build.
 ///
 /// This is created from [`BuildDirectory::build`](struct.BuildDirectory.html#method.build)
@@ -192,6 +685,10 @@ pub struct Build<'b> {
     dir: &'b BuildDirectory,
     toolchain: &'b Toolchain,
     sandbox: SandboxBuilder,
+    env: Vec<(&'static str, String)>,
+    target_dir: PathBuf,
+    profile_args: Vec<String>,
+    sccache_stats: bool,
 }
 
 impl Build<'_> {
@@ -201,6 +698,10 @@ impl Build<'_> {
     /// outside the sandbox. The crate's source directory will be the working directory for the
     /// command.
     ///
+    /// The crate's dependencies are fetched with networking enabled while preparing the build, so
+    /// if the sandbox's networking is disabled `cargo` will be configured to run offline
+    /// (through the `CARGO_NET_OFFLINE` environment variable).
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -222,15 +723,26 @@ impl Build<'_> {
     pub fn cmd<R: Runnable>(&self, bin: R) -> Command {
         let container_dir = &*crate::cmd::container_dirs::TARGET_DIR;
 
-        Command::new_sandboxed(
+        let mut cmd = Command::new_sandboxed(
             &self.dir.workspace,
-            self.sandbox
-                .clone()
-                .mount(&self.dir.target_dir(), container_dir, MountKind::ReadWrite),
+            self.sandbox.clone().mount_internal(
+                &self.target_dir,
+                container_dir,
+                MountKind::ReadWrite,
+            ),
             bin,
         )
         .cd(self.dir.source_dir())
-        .env("CARGO_TARGET_DIR", container_dir)
+        .env("CARGO_TARGET_DIR", container_dir);
+        for (key, value) in &self.env {
+            cmd = cmd.env(key, value);
+        }
+
+        if self.sandbox.networking_enabled() {
+            cmd
+        } else {
+            cmd.env("CARGO_NET_OFFLINE", "true")
+        }
     }
 
     /// Run `cargo` inside the sandbox, using the toolchain chosen for the build.
@@ -257,7 +769,144 @@ impl Build<'_> {
     /// # }
     /// ```
     pub fn cargo(&self) -> Command {
-        self.cmd(self.toolchain.cargo())
+        let cmd = if self.sccache_stats {
+            self.cmd(CargoWithSccacheStats {
+                toolchain: self.toolchain,
+            })
+        } else {
+            self.cmd(self.toolchain.cargo())
+        };
+        cmd.subcommand_args(PROFILE_SUBCOMMANDS, &self.profile_args)
+    }
+
+    /// Run `cargo` inside the sandbox with `--message-format=json`, calling the provided function
+    /// with each message emitted by cargo, like the diagnostics and the artifacts produced by the
+    /// compiler. The first argument must be the cargo subcommand to execute.
+    ///
+    /// The JSON messages are not logged: the rendered diagnostics are logged in their place.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, CargoMessage, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox).run(|build| {
+    ///     let mut warnings = 0;
+    ///     build.cargo_messages(&["check", "--all"], |message| {
+    ///         if let CargoMessage::CompilerMessage(msg) = message {
+    ///             if msg.diagnostic().level() == "warning" {
+    ///                 warnings += 1;
+    ///             }
+    ///         }
+    ///     })?;
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cargo_messages<F: FnMut(CargoMessage)>(
+        &self,
+        args: &[&str],
+        mut f: F,
+    ) -> Result<(), Error> {
+        let (subcommand, args) = args.split_at(args.len().min(1));
+        let mut process_lines = |line: &str, actions: &mut ProcessLinesActions| {
+            if let Some(message) = crate::cargo_messages::parse(line) {
+                match &message {
+                    CargoMessage::CompilerMessage(msg) => match msg.diagnostic().rendered() {
+                        Some(rendered) => actions.replace_with(rendered.trim_end()),
+                        None => actions.remove_line(),
+                    },
+                    _ => actions.remove_line(),
+                }
+                f(message);
+            }
+        };
+        self.cargo()
+            .args(subcommand)
+            .args(&["--message-format=json"])
+            .args(args)
+            .process_lines(&mut process_lines)
+            .run()
+    }
+
+    /// Run `cargo` inside the sandbox with `--message-format=json`, returning the diagnostics
+    /// emitted by the compiler. The first argument must be the cargo subcommand to execute.
+    ///
+    /// If cargo fails the returned [`CargoFailure`](struct.CargoFailure.html) contains both the
+    /// diagnostics emitted before the failure and the original error.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use rustwide::{WorkspaceBuilder, Toolchain, Crate, cmd::SandboxBuilder};
+    /// # use std::error::Error;
+    /// # fn main() -> Result<(), Box<dyn Error>> {
+    /// # let workspace = WorkspaceBuilder::new("".as_ref(), "").init()?;
+    /// # let toolchain = Toolchain::Dist { name: "".into() };
+    /// # let krate = Crate::local("".as_ref());
+    /// # let sandbox = SandboxBuilder::new();
+    /// let mut build_dir = workspace.build_dir("foo");
+    /// build_dir.build(&toolchain, &krate, sandbox).run(|build| {
+    ///     if let Err(failure) = build.cargo_diagnostics(&["build"]) {
+    ///         for msg in failure.errors() {
+    ///             println!("{:?}: {}", msg.diagnostic().code(), msg.diagnostic().message());
+    ///         }
+    ///         return Err(failure.into());
+    ///     }
+    ///     Ok(())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cargo_diagnostics(&self, args: &[&str]) -> Result<Vec<CompilerMessage>, CargoFailure> {
+        let mut diagnostics = Vec::new();
+        let res = self.cargo_messages(args, |message| {
+            if let CargoMessage::CompilerMessage(msg) = message {
+                diagnostics.push(msg);
+            }
+        });
+        match res {
+            Ok(()) => Ok(diagnostics),
+            Err(err) => Err(CargoFailure::new(diagnostics, err)),
+        }
+    }
+
+    /// Get the metadata of the crate being built, like its features and dependencies, through
+    /// `cargo metadata`. The command is executed outside the sandbox, and it doesn't resolve the
+    /// dependencies.
+    pub fn metadata(&self) -> Result<PackageMetadata, Error> {
+        let out = Command::new(&self.dir.workspace, self.toolchain.cargo())
+            .args(&["metadata", "--no-deps", "--format-version", "1"])
+            .args(&["--manifest-path", "Cargo.toml"])
+            .cd(self.dir.source_dir())
+            .log_output(false)
+            .run_capture()?;
+        crate::metadata::parse(&out.stdout_lines().join("\n"))
+    }
+
+    /// Get the contents of the crate's `Cargo.lock`, which contains the exact version of all the
+    /// dependencies used by the build.
+    pub fn lockfile(&self) -> Result<String, Error> {
+        Ok(std::fs::read_to_string(
+            self.dir.source_dir().join("Cargo.lock"),
+        )?)
+    }
+
+    /// Take a snapshot of the files in the source directory, which can be compared later with
+    /// [`SourceSnapshot::changes`](struct.SourceSnapshot.html#method.changes) to find out which
+    /// files were added, modified or removed by the commands executed in the meantime.
+    ///
+    /// This can be used to audit crates writing outside of the target directory. The files in the
+    /// target directory are not part of the snapshot.
+    pub fn snapshot_source(&self) -> Result<SourceSnapshot, Error> {
+        SourceSnapshot::new(&self.dir.source_dir())
     }
 
     /// Get the path to the source code on the host machine (outside the sandbox).
@@ -265,8 +914,95 @@ impl Build<'_> {
         self.dir.source_dir()
     }
 
+    /// Get the path to the crate's `Cargo.toml` on the host machine (outside the sandbox).
+    ///
+    /// The manifest is always at the root of the source directory, including for git crates
+    /// using [`Crate::path_in_repo`](struct.Crate.html#method.path_in_repo), as only that
+    /// directory is copied. Its name, version and edition can be retrieved with
+    /// [`metadata`](#method.metadata).
+    pub fn host_manifest_path(&self) -> PathBuf {
+        self.dir.source_dir().join("Cargo.toml")
+    }
+
     /// Get the path to the target directory on the host machine (outside the sandbox).
     pub fn host_target_dir(&self) -> PathBuf {
-        self.dir.target_dir()
+        self.target_dir.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Crate;
+    use failure::Error;
+    use std::path::Path;
+
+    #[test]
+    fn test_check_target_dir_crate() -> Result<(), Error> {
+        let target_dir = tempfile::tempdir()?;
+        let foo = Crate::local(Path::new("/path/to/foo"));
+        let bar = Crate::local(Path::new("/path/to/bar"));
+
+        super::check_target_dir_crate(target_dir.path(), &foo)?;
+        super::check_target_dir_crate(target_dir.path(), &foo)?;
+        assert!(super::check_target_dir_crate(target_dir.path(), &bar).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_target_dir_crate_concurrent() -> Result<(), Error> {
+        let target_dir = tempfile::tempdir()?;
+
+        let threads = (0..8)
+            .map(|i| {
+                let target_dir = target_dir.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let krate = Crate::local(&Path::new("/path/to").join(i.to_string()));
+                    super::check_target_dir_crate(&target_dir, &krate).is_ok()
+                })
+            })
+            .collect::<Vec<_>>();
+        let succeeded = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|&ok| ok)
+            .count();
+        assert_eq!(succeeded, 1);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sccache_stats_script() -> Result<(), Error> {
+        let run = |cmd: &str| {
+            std::process::Command::new("sh")
+                .args(&["-c", super::SCCACHE_STATS_SCRIPT, "sh", cmd, "foo bar"])
+                .env("RUSTC_WRAPPER", "echo")
+                .output()
+        };
+
+        let out = run("echo")?;
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8(out.stdout)?, "foo bar\n--show-stats\n");
+
+        // The exit status of the command is preserved.
+        let out = run("false")?;
+        assert_eq!(out.status.code(), Some(1));
+        assert_eq!(String::from_utf8(out.stdout)?, "--show-stats\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rustc_wrapper_sandbox_path() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let wrapper = dir.path().join("sccache");
+        std::fs::write(&wrapper, b"")?;
+
+        let sandbox_path = super::rustc_wrapper_sandbox_path(&wrapper)?;
+        assert_eq!(sandbox_path.file_name(), wrapper.file_name());
+        assert!(super::rustc_wrapper_sandbox_path(dir.path()).is_err());
+        assert!(super::rustc_wrapper_sandbox_path(&dir.path().join("missing")).is_err());
+        assert!(super::rustc_wrapper_sandbox_path(Path::new("/")).is_err());
+        assert!(super::rustc_wrapper_sandbox_path(&wrapper.join("..")).is_err());
+        Ok(())
     }
 }
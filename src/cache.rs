@@ -0,0 +1,325 @@
+use failure::Error;
+use log::{info, warn};
+use remove_dir_all::remove_dir_all;
+use std::fs::OpenOptions;
+use std::panic::RefUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// Cache subdirectories containing one entry per cached crate, and whether the entries are nested
+/// in a directory named after the crate.
+const CACHE_DIRS: &[(&str, bool)] = &[("cratesio-sources", true), ("git-repos", false)];
+
+/// Policy used by [`Workspace::purge_cache`](struct.Workspace.html#method.purge_cache) to choose
+/// which cached crates to remove.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePurgePolicy {
+    /// Remove all the cached crates that weren't fetched in the provided amount of time.
+    OlderThan(Duration),
+    /// Remove the least recently fetched crates until the size of the cache, in bytes, is lower
+    /// than the provided one.
+    MaxSize(u64),
+    #[doc(hidden)]
+    __NonExaustive,
+}
+
+/// Strategy choosing where the `.crate` files downloaded by rustwide are cached, configured with
+/// [`WorkspaceBuilder::crate_cache_layout`](struct.WorkspaceBuilder.html#method.crate_cache_layout).
+///
+/// By default the files are stored in the workspace's own cache directory, but a custom layout
+/// allows sharing them with other tools, for example by pointing to cargo's registry cache.
+///
+/// While downloading a crate rustwide creates two files next to its cached path, which must then
+/// be in a directory writable by the current user: a `.lock` file (kept after the download, to
+/// coordinate concurrent fetches of the same crate) and a temporary file starting with `.download`
+/// (renamed to the final path once the download is verified).
+pub trait CrateCacheLayout: Send + Sync + RefUnwindSafe {
+    /// Return the path where the `.crate` file of the provided crate version is cached. `index` is
+    /// the URL of the index of the registry the crate is downloaded from.
+    ///
+    /// The name and version are validated before this method is called, so they're safe to use
+    /// as path components.
+    fn crate_cache_path(&self, name: &str, version: &str, index: &str) -> PathBuf;
+}
+
+/// Default layout, storing the `.crate` files in the workspace's cache directory.
+pub(crate) struct WorkspaceCacheLayout {
+    cache_dir: PathBuf,
+}
+
+impl WorkspaceCacheLayout {
+    pub(crate) fn new(cache_dir: &Path) -> Self {
+        WorkspaceCacheLayout {
+            cache_dir: cache_dir.into(),
+        }
+    }
+}
+
+impl CrateCacheLayout for WorkspaceCacheLayout {
+    fn crate_cache_path(&self, name: &str, version: &str, _index: &str) -> PathBuf {
+        self.cache_dir
+            .join("cratesio-sources")
+            .join(name)
+            .join(format!("{}-{}.crate", name, version))
+    }
+}
+
+struct CacheEntry {
+    path: PathBuf,
+    nested: bool,
+    last_used: SystemTime,
+    size: u64,
+}
+
+/// Remove the entries of the cache matching the policy.
+///
+/// Crates cached outside the cache directory by a custom
+/// [`CrateCacheLayout`](trait.CrateCacheLayout.html) are not purged.
+///
+/// The caller is responsible for making sure no other process is using the cache.
+pub(crate) fn purge(cache_dir: &Path, policy: CachePurgePolicy) -> Result<(), Error> {
+    let mut entries = cache_entries(cache_dir)?;
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let now = SystemTime::now();
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    for entry in entries {
+        let remove = match policy {
+            CachePurgePolicy::OlderThan(max_age) => now
+                .duration_since(entry.last_used)
+                .map(|age| age > max_age)
+                .unwrap_or(false),
+            CachePurgePolicy::MaxSize(max_size) => total_size > max_size,
+            CachePurgePolicy::__NonExaustive => {
+                panic!("do not create __NonExaustive variants manually")
+            }
+        };
+        if !remove {
+            continue;
+        }
+
+        info!("purging {} from the cache", entry.path.display());
+        if entry.path.is_dir() {
+            remove_dir_all(&entry.path)?;
+        } else {
+            std::fs::remove_file(&entry.path)?;
+        }
+        let lock = lock_path(&entry.path);
+        if lock.exists() {
+            std::fs::remove_file(&lock)?;
+        }
+        total_size -= entry.size;
+
+        // Remove the directory named after the crate if this was its last cached version.
+        if let (true, Some(parent)) = (entry.nested, entry.path.parent()) {
+            if std::fs::read_dir(parent)?.next().is_none() {
+                std::fs::remove_dir(parent)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Mark a cached file as recently used, so that it's the last one to be purged.
+pub(crate) fn touch(path: &Path) {
+    let res = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = res {
+        warn!("failed to update the mtime of {}: {}", path.display(), err);
+    }
+}
+
+fn cache_entries(cache_dir: &Path) -> Result<Vec<CacheEntry>, Error> {
+    let mut entries = Vec::new();
+    for &(dir, nested) in CACHE_DIRS {
+        let dir = cache_dir.join(dir);
+        if !dir.exists() {
+            continue;
+        }
+
+        let depth = if nested { 2 } else { 1 };
+        for entry in WalkDir::new(&dir).min_depth(depth).max_depth(depth) {
+            let entry = entry?;
+            if !is_auxiliary_file(entry.path()) {
+                entries.push(cache_entry(entry.path(), nested)?);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Lock files and partial downloads are stored next to the cache entries, but they're not entries
+/// themselves.
+fn is_auxiliary_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".lock") || name.ends_with(".tmp") || name.starts_with(".download")
+}
+
+/// Path of the lock file taken while an entry is being fetched.
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_os_string();
+    lock.push(".lock");
+    lock.into()
+}
+
+/// Calculate the size of an entry, and when it was last used from the most recent mtime of its
+/// files.
+fn cache_entry(path: &Path, nested: bool) -> Result<CacheEntry, Error> {
+    let mut last_used = SystemTime::UNIX_EPOCH;
+    let mut size = 0;
+    for file in WalkDir::new(path) {
+        let metadata = file?.metadata()?;
+        last_used = last_used.max(metadata.modified()?);
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(CacheEntry {
+        path: path.into(),
+        nested,
+        last_used,
+        size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CachePurgePolicy;
+    use failure::Error;
+    use std::fs::OpenOptions;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn create_entry(path: &Path, size: usize, age: Duration) -> Result<(), Error> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, vec![0; size])?;
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now() - age)?;
+        Ok(())
+    }
+
+    fn create_cache(cache: &Path) -> Result<(), Error> {
+        let hour = Duration::from_secs(3600);
+        create_entry(
+            &cache.join("cratesio-sources/foo/foo-1.0.0.crate"),
+            100,
+            hour * 3,
+        )?;
+        create_entry(
+            &cache.join("cratesio-sources/foo/foo-2.0.0.crate"),
+            100,
+            hour,
+        )?;
+        create_entry(
+            &cache.join("cratesio-sources/bar/bar-1.0.0.crate"),
+            100,
+            hour * 4,
+        )?;
+        create_entry(&cache.join("git-repos/repo/HEAD"), 100, hour * 2)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_workspace_cache_layout() {
+        use super::{CrateCacheLayout, WorkspaceCacheLayout};
+
+        let layout = WorkspaceCacheLayout::new(Path::new("/opt/cache"));
+        assert_eq!(
+            layout.crate_cache_path("foo", "1.0.0", "https://index.crates.io"),
+            Path::new("/opt/cache/cratesio-sources/foo/foo-1.0.0.crate")
+        );
+    }
+
+    #[test]
+    fn test_purge_older_than() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let cache = dir.path();
+        create_cache(cache)?;
+
+        super::purge(
+            cache,
+            CachePurgePolicy::OlderThan(Duration::from_secs(150 * 60)),
+        )?;
+        assert!(!cache.join("cratesio-sources/foo/foo-1.0.0.crate").exists());
+        assert!(cache.join("cratesio-sources/foo/foo-2.0.0.crate").exists());
+        assert!(!cache.join("cratesio-sources/bar").exists());
+        assert!(cache.join("git-repos/repo").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_with_lock_files() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let cache = dir.path();
+        create_cache(cache)?;
+        let hour = Duration::from_secs(3600);
+        create_entry(
+            &cache.join("cratesio-sources/foo/foo-1.0.0.crate.lock"),
+            0,
+            hour * 5,
+        )?;
+        create_entry(
+            &cache.join("cratesio-sources/foo/foo-2.0.0.crate.lock"),
+            0,
+            hour * 5,
+        )?;
+        create_entry(
+            &cache.join("cratesio-sources/baz/baz-1.0.0.crate.tmp"),
+            1000,
+            hour * 5,
+        )?;
+        create_entry(
+            &cache.join("cratesio-sources/baz/.download1234"),
+            1000,
+            hour * 5,
+        )?;
+        create_entry(&cache.join("git-repos/repo.lock"), 0, hour * 5)?;
+
+        super::purge(
+            cache,
+            CachePurgePolicy::OlderThan(Duration::from_secs(150 * 60)),
+        )?;
+        // Locks are removed together with their entries, and never purged on their own.
+        assert!(!cache.join("cratesio-sources/foo/foo-1.0.0.crate").exists());
+        assert!(!cache
+            .join("cratesio-sources/foo/foo-1.0.0.crate.lock")
+            .exists());
+        assert!(cache.join("cratesio-sources/foo/foo-2.0.0.crate").exists());
+        assert!(cache
+            .join("cratesio-sources/foo/foo-2.0.0.crate.lock")
+            .exists());
+        assert!(cache.join("git-repos/repo").exists());
+        assert!(cache.join("git-repos/repo.lock").exists());
+        // Partial downloads are not entries.
+        assert!(cache
+            .join("cratesio-sources/baz/baz-1.0.0.crate.tmp")
+            .exists());
+        assert!(cache.join("cratesio-sources/baz/.download1234").exists());
+
+        // Partial downloads don't count towards the size of the cache.
+        super::purge(cache, CachePurgePolicy::MaxSize(200))?;
+        assert!(cache.join("cratesio-sources/foo/foo-2.0.0.crate").exists());
+        assert!(cache.join("git-repos/repo").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_max_size() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let cache = dir.path();
+        create_cache(cache)?;
+
+        super::purge(cache, CachePurgePolicy::MaxSize(250))?;
+        assert!(!cache.join("cratesio-sources/bar").exists());
+        assert!(!cache.join("cratesio-sources/foo/foo-1.0.0.crate").exists());
+        assert!(cache.join("cratesio-sources/foo/foo-2.0.0.crate").exists());
+        assert!(cache.join("git-repos/repo").exists());
+        Ok(())
+    }
+}
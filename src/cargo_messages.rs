@@ -0,0 +1,331 @@
+use failure::{Error, Fail};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Message emitted by cargo when it's invoked with `--message-format=json`.
+///
+/// This is returned by [`Build::cargo_messages`](struct.Build.html#method.cargo_messages).
+#[derive(Debug, Clone)]
+pub enum CargoMessage {
+    /// Diagnostic emitted by the compiler, like an error or a warning.
+    CompilerMessage(CompilerMessage),
+    /// Artifact produced by the compiler.
+    CompilerArtifact(CompilerArtifact),
+    /// The build finished. The first value is whether the build succeeded.
+    BuildFinished(bool),
+    /// Message of a kind rustwide doesn't parse. The first value is the message's `reason`.
+    Other(String),
+    #[doc(hidden)]
+    __NonExaustive,
+}
+
+/// Diagnostic emitted by the compiler while building a target.
+#[derive(Debug, Clone)]
+pub struct CompilerMessage {
+    package_id: String,
+    target_name: String,
+    diagnostic: Diagnostic,
+}
+
+impl CompilerMessage {
+    /// Return the ID of the package containing the target being built.
+    pub fn package_id(&self) -> &str {
+        &self.package_id
+    }
+
+    /// Return the name of the target being built.
+    pub fn target_name(&self) -> &str {
+        &self.target_name
+    }
+
+    /// Return the diagnostic emitted by the compiler.
+    pub fn diagnostic(&self) -> &Diagnostic {
+        &self.diagnostic
+    }
+}
+
+/// Diagnostic emitted by the compiler.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Diagnostic {
+    message: String,
+    code: Option<DiagnosticCode>,
+    level: String,
+    #[serde(default)]
+    spans: Vec<DiagnosticSpan>,
+    rendered: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct DiagnosticCode {
+    code: String,
+}
+
+impl Diagnostic {
+    /// Return the main message of the diagnostic.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Return the code of the diagnostic (for example `E0308`), if it has one.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_ref().map(|code| code.code.as_str())
+    }
+
+    /// Return the level of the diagnostic, for example `error` or `warning`.
+    pub fn level(&self) -> &str {
+        &self.level
+    }
+
+    /// Return the locations in the source code the diagnostic refers to.
+    pub fn spans(&self) -> &[DiagnosticSpan] {
+        &self.spans
+    }
+
+    /// Return the diagnostic rendered in the same way the compiler prints it to the console.
+    pub fn rendered(&self) -> Option<&str> {
+        self.rendered.as_deref()
+    }
+}
+
+/// Location in the source code a [`Diagnostic`](struct.Diagnostic.html) refers to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiagnosticSpan {
+    file_name: String,
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    column_end: usize,
+    is_primary: bool,
+    label: Option<String>,
+}
+
+impl DiagnosticSpan {
+    /// Return the path of the file, relative to the root of the crate's package. For files
+    /// outside of the package (like dependencies) the path is the one inside the sandbox.
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    /// Return the first line of the span, starting from 1.
+    pub fn line_start(&self) -> usize {
+        self.line_start
+    }
+
+    /// Return the last line of the span, starting from 1.
+    pub fn line_end(&self) -> usize {
+        self.line_end
+    }
+
+    /// Return the first column of the span, starting from 1.
+    pub fn column_start(&self) -> usize {
+        self.column_start
+    }
+
+    /// Return the column after the end of the span, starting from 1.
+    pub fn column_end(&self) -> usize {
+        self.column_end
+    }
+
+    /// Return whether this is the primary location of the diagnostic.
+    pub fn is_primary(&self) -> bool {
+        self.is_primary
+    }
+
+    /// Return the label attached to the span, if the compiler added one.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// Error returned by [`Build::cargo_diagnostics`](struct.Build.html#method.cargo_diagnostics)
+/// when cargo fails, containing the diagnostics emitted by the compiler before the failure.
+#[derive(Debug)]
+pub struct CargoFailure {
+    diagnostics: Vec<CompilerMessage>,
+    error: Error,
+}
+
+impl CargoFailure {
+    pub(crate) fn new(diagnostics: Vec<CompilerMessage>, error: Error) -> Self {
+        CargoFailure { diagnostics, error }
+    }
+
+    /// Return all the diagnostics emitted by the compiler, including the warnings.
+    pub fn diagnostics(&self) -> &[CompilerMessage] {
+        &self.diagnostics
+    }
+
+    /// Return the diagnostics with the `error` level emitted by the compiler.
+    pub fn errors(&self) -> impl Iterator<Item = &CompilerMessage> {
+        self.diagnostics
+            .iter()
+            .filter(|msg| msg.diagnostic().level() == "error")
+    }
+
+    /// Return the error returned while running cargo, for example a
+    /// [`CommandError`](cmd/enum.CommandError.html).
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+}
+
+impl fmt::Display for CargoFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (with {} errors reported by the compiler)",
+            self.error,
+            self.errors().count()
+        )
+    }
+}
+
+impl Fail for CargoFailure {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.error.as_fail())
+    }
+}
+
+/// Artifact produced by the compiler while building a target.
+#[derive(Debug, Clone)]
+pub struct CompilerArtifact {
+    package_id: String,
+    target_name: String,
+    filenames: Vec<PathBuf>,
+    executable: Option<PathBuf>,
+    fresh: bool,
+}
+
+impl CompilerArtifact {
+    /// Return the ID of the package containing the target that was built.
+    pub fn package_id(&self) -> &str {
+        &self.package_id
+    }
+
+    /// Return the name of the target that was built.
+    pub fn target_name(&self) -> &str {
+        &self.target_name
+    }
+
+    /// Return the paths of the files produced by the compiler. For sandboxed builds the paths are
+    /// the ones inside the sandbox.
+    pub fn filenames(&self) -> &[PathBuf] {
+        &self.filenames
+    }
+
+    /// Return the path of the executable produced by the compiler, if the target is an
+    /// executable. For sandboxed builds the path is the one inside the sandbox.
+    pub fn executable(&self) -> Option<&PathBuf> {
+        self.executable.as_ref()
+    }
+
+    /// Return whether the artifact was already built before, and it wasn't rebuilt.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    reason: String,
+    package_id: Option<String>,
+    target: Option<RawTarget>,
+    message: Option<Diagnostic>,
+    #[serde(default)]
+    filenames: Vec<PathBuf>,
+    executable: Option<PathBuf>,
+    #[serde(default)]
+    fresh: bool,
+    success: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct RawTarget {
+    name: String,
+}
+
+/// Parse a line of output of cargo invoked with `--message-format=json`, returning `None` if the
+/// line isn't a JSON message.
+pub(crate) fn parse(line: &str) -> Option<CargoMessage> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let raw: RawMessage = serde_json::from_str(line).ok()?;
+
+    let package_id = raw.package_id.unwrap_or_default();
+    let target_name = raw.target.map(|target| target.name).unwrap_or_default();
+    Some(match (raw.reason.as_str(), raw.message, raw.success) {
+        ("compiler-message", Some(diagnostic), _) => {
+            CargoMessage::CompilerMessage(CompilerMessage {
+                package_id,
+                target_name,
+                diagnostic,
+            })
+        }
+        ("compiler-artifact", _, _) => CargoMessage::CompilerArtifact(CompilerArtifact {
+            package_id,
+            target_name,
+            filenames: raw.filenames,
+            executable: raw.executable,
+            fresh: raw.fresh,
+        }),
+        ("build-finished", _, Some(success)) => CargoMessage::BuildFinished(success),
+        _ => CargoMessage::Other(raw.reason),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CargoMessage;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse() {
+        let message = r#"{"reason":"compiler-message","package_id":"foo 0.1.0 (path+file:///foo)","target":{"name":"foo","kind":["lib"]},"message":{"message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"src/lib.rs","byte_start":30,"byte_end":32,"line_start":2,"line_end":2,"column_start":18,"column_end":20,"is_primary":true,"text":[],"label":"expected `u32`, found `&str`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error[E0308]: mismatched types\n"}}"#;
+        match super::parse(message) {
+            Some(CargoMessage::CompilerMessage(msg)) => {
+                assert_eq!(msg.package_id(), "foo 0.1.0 (path+file:///foo)");
+                assert_eq!(msg.target_name(), "foo");
+                assert_eq!(msg.diagnostic().message(), "mismatched types");
+                assert_eq!(msg.diagnostic().code(), Some("E0308"));
+                assert_eq!(msg.diagnostic().level(), "error");
+                let span = &msg.diagnostic().spans()[0];
+                assert_eq!(span.file_name(), "src/lib.rs");
+                assert_eq!((span.line_start(), span.line_end()), (2, 2));
+                assert_eq!((span.column_start(), span.column_end()), (18, 20));
+                assert!(span.is_primary());
+                assert_eq!(span.label(), Some("expected `u32`, found `&str`"));
+                assert_eq!(
+                    msg.diagnostic().rendered(),
+                    Some("error[E0308]: mismatched types\n")
+                );
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        let artifact = r#"{"reason":"compiler-artifact","package_id":"foo 0.1.0 (path+file:///foo)","target":{"name":"foo","kind":["bin"]},"filenames":["/target/debug/foo"],"executable":"/target/debug/foo","fresh":true}"#;
+        match super::parse(artifact) {
+            Some(CargoMessage::CompilerArtifact(artifact)) => {
+                assert_eq!(artifact.target_name(), "foo");
+                assert_eq!(artifact.filenames(), &[Path::new("/target/debug/foo")]);
+                assert_eq!(
+                    artifact.executable().map(|p| p.as_path()),
+                    Some(Path::new("/target/debug/foo"))
+                );
+                assert!(artifact.is_fresh());
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        match super::parse(r#"{"reason":"build-finished","success":false}"#) {
+            Some(CargoMessage::BuildFinished(false)) => {}
+            other => panic!("unexpected message: {:?}", other),
+        }
+        match super::parse(r#"{"reason":"build-script-executed","package_id":"foo"}"#) {
+            Some(CargoMessage::Other(reason)) => assert_eq!(reason, "build-script-executed"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(super::parse("   Compiling foo v0.1.0").is_none());
+    }
+}
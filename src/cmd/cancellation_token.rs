@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Token used to cancel running [`Command`](struct.Command.html)s from another thread.
+///
+/// The token can be cloned, and all the clones refer to the same cancellation state: cancelling
+/// any of them cancels all the commands using one of its clones. Once a token is cancelled it
+/// can't be reset, and commands started with it are cancelled right away.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a new token, which is not cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel all the commands using this token. The commands are killed and they return the
+    /// [`CommandError::Cancelled`](enum.CommandError.html#variant.Cancelled) error.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether this token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
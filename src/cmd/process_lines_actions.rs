@@ -0,0 +1,42 @@
+/// Actions that can be performed on a line of output, from the function passed to
+/// [`Command::process_lines`](struct.Command.html#method.process_lines).
+///
+/// The actions are applied before the line is logged or captured. If no action is performed the
+/// original line is kept.
+pub struct ProcessLinesActions {
+    state: ActionsState,
+}
+
+enum ActionsState {
+    Original,
+    Replaced(String),
+    Removed,
+}
+
+impl ProcessLinesActions {
+    pub(super) fn new() -> Self {
+        ProcessLinesActions {
+            state: ActionsState::Original,
+        }
+    }
+
+    /// Replace the current line with the provided one, for example to redact secrets from the
+    /// output. Calling this method again overrides the previous replacement.
+    pub fn replace_with<S: Into<String>>(&mut self, line: S) {
+        self.state = ActionsState::Replaced(line.into());
+    }
+
+    /// Remove the current line from the output, so that it's neither logged nor captured.
+    pub fn remove_line(&mut self) {
+        self.state = ActionsState::Removed;
+    }
+
+    /// Apply the requested actions to the original line, returning `None` if it was removed.
+    pub(super) fn apply(self, original: String) -> Option<String> {
+        match self.state {
+            ActionsState::Original => Some(original),
+            ActionsState::Replaced(line) => Some(line),
+            ActionsState::Removed => None,
+        }
+    }
+}
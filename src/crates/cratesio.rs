@@ -1,89 +1,291 @@
-use super::CrateTrait;
+use super::download::{download_to, DownloadProgress, NoProgress};
+use super::index::{self, IndexEntry};
+use super::{CrateTrait, DownloadInfo};
+use crate::archive::UnpackOptions;
+use crate::prepare::PrepareError;
 use crate::Workspace;
 use failure::Error;
-use flate2::read::GzDecoder;
-use log::info;
+use futures::future::{self, Either};
+use futures::Future;
+use log::{info, warn};
 use remove_dir_all::remove_dir_all;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read};
+use reqwest::header::CONTENT_LENGTH;
+use semver::Version;
 use std::path::{Path, PathBuf};
-use tar::Archive;
+use std::sync::{Arc, Mutex};
 
-static CRATES_ROOT: &str = "https://static.crates.io/crates";
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Crate download prepared after resolving the version from the index.
+struct PendingDownload {
+    version: String,
+    url: String,
+    local: PathBuf,
+    cksum: String,
+}
 
 impl CratesIOCrate {
     pub(super) fn new(name: &str, version: &str) -> Self {
         CratesIOCrate {
             name: name.into(),
-            version: version.into(),
+            version: Mutex::new(Some(version.into())),
         }
     }
 
-    fn cache_path(&self, workspace: &Workspace) -> PathBuf {
-        workspace
-            .cache_dir()
-            .join("cratesio-sources")
-            .join(&self.name)
-            .join(format!("{}-{}.crate", self.name, self.version))
+    pub(super) fn latest(name: &str) -> Self {
+        CratesIOCrate {
+            name: name.into(),
+            version: Mutex::new(None),
+        }
     }
-}
 
-pub(super) struct CratesIOCrate {
-    name: String,
-    version: String,
-}
+    /// Return the version of this crate, or `None` if it's the latest version and it wasn't
+    /// resolved yet.
+    pub(super) fn version(&self) -> Option<String> {
+        self.version.lock().unwrap().clone()
+    }
 
-impl CrateTrait for CratesIOCrate {
-    fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
-        let local = self.cache_path(workspace);
-        if local.exists() {
-            info!("crate {} {} is already in cache", self.name, self.version);
-            return Ok(());
+    fn resolved_version(&self) -> Result<String, Error> {
+        self.version().ok_or_else(|| {
+            failure::format_err!(
+                "the latest version of crate {} wasn't resolved, fetch the crate first",
+                self.name
+            )
+        })
+    }
+
+    fn resolve_version(&self, workspace: &Workspace) -> Result<String, Error> {
+        validate_name(&self.name)?;
+        let mut version = self.version.lock().unwrap();
+        if let Some(version) = &*version {
+            validate_version(&self.name, version)?;
+            return Ok(version.clone());
+        }
+
+        info!("resolving the latest version of crate {}", self.name);
+        let entries = index::crates_io_entries(workspace, &self.name)?;
+        let latest = self.latest_version(&entries)?;
+        *version = Some(latest.clone());
+        Ok(latest)
+    }
+
+    fn latest_version(&self, entries: &[IndexEntry]) -> Result<String, Error> {
+        let latest = index::latest_version(entries).ok_or_else(|| {
+            failure::format_err!("crate {} doesn't have any non-yanked version", self.name)
+        })?;
+        info!("the latest version of crate {} is {}", self.name, latest);
+        Ok(latest)
+    }
+
+    pub(super) fn is_yanked(&self, workspace: &Workspace) -> Result<bool, Error> {
+        let version = self.resolve_version(workspace)?;
+        Ok(index::crates_io_entry(workspace, &self.name, &version)?.yanked)
+    }
+
+    fn download_url(&self, workspace: &Workspace, version: &str) -> String {
+        format!(
+            "{0}/{1}/{1}-{2}.crate",
+            workspace.crates_io_source_root(),
+            self.name,
+            version
+        )
+    }
+
+    pub(super) fn download_info(&self, workspace: &Workspace) -> Result<DownloadInfo, Error> {
+        let version = self.resolve_version(workspace)?;
+        let url = self.download_url(workspace, &version);
+        let mut req = workspace.http_client().head(&url);
+        if let Some((username, password)) = workspace.crates_io_credentials() {
+            req = req.basic_auth(username, password.as_ref());
+        }
+        let resp = req.send()?.error_for_status()?;
+        let size = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        Ok(DownloadInfo { url, size })
+    }
+
+    fn cache_path(&self, workspace: &Workspace, version: &str) -> Result<PathBuf, Error> {
+        // Validating the name and version ensures they can't escape the cache directory.
+        validate_name(&self.name)?;
+        validate_version(&self.name, version)?;
+        Ok(workspace.crate_cache_layout().crate_cache_path(
+            &self.name,
+            version,
+            index::CRATES_IO_INDEX,
+        ))
+    }
+
+    /// Blocking version of `fetch_async`, which must be called while holding the cache lock.
+    pub(super) fn fetch_with_progress(
+        &self,
+        workspace: &Workspace,
+        progress: Option<DownloadProgress>,
+    ) -> Result<(), Error> {
+        crate::utils::block_on(self.fetch_unlocked(workspace, progress))
+    }
+
+    pub(super) fn fetch_async<'a>(
+        &'a self,
+        workspace: &'a Workspace,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send + 'a> {
+        // The cache lock is held until the download is complete, so that the cache isn't purged
+        // while the download is in progress.
+        Box::new(
+            workspace
+                .lock_cache_async(true, "purge the cache")
+                .and_then(move |cache_lock| {
+                    self.fetch_unlocked(workspace, None::<NoProgress>)
+                        .then(move |res| {
+                            drop(cache_lock);
+                            res
+                        })
+                }),
+        )
+    }
+
+    /// Fetch the crate without taking the cache lock, which is up to the caller.
+    fn fetch_unlocked<'a, P: FnMut(u64, Option<u64>) + 'a>(
+        &'a self,
+        workspace: &'a Workspace,
+        progress: Option<P>,
+    ) -> impl Future<Item = (), Error = Error> + 'a {
+        if let Err(err) = validate_name(&self.name) {
+            return Either::A(future::err(err));
+        }
+        // Crates already in the cache don't need to query the index, unless the version has to be
+        // resolved.
+        if let Some(version) = self.version() {
+            match self.cache_path(workspace, &version) {
+                Ok(local) if local.exists() => {
+                    info!("crate {} {} is already in cache", self.name, version);
+                    crate::cache::touch(&local);
+                    return Either::A(future::ok(()));
+                }
+                Ok(_) => {}
+                Err(err) => return Either::A(future::err(err)),
+            }
         }
 
-        info!("fetching crate {} {}...", self.name, self.version);
+        Either::B(
+            index::crates_io_entries_async(workspace, &self.name)
+                .and_then(move |entries| self.prepare_download(workspace, entries))
+                .and_then(move |download| match download {
+                    Some(download) => Either::A(self.download(workspace, download, progress)),
+                    None => Either::B(future::ok(())),
+                }),
+        )
+    }
+
+    /// Resolve the version to download from the index entries, returning what needs to be
+    /// downloaded, or `None` if the crate is already cached.
+    fn prepare_download(
+        &self,
+        workspace: &Workspace,
+        entries: Vec<IndexEntry>,
+    ) -> Result<Option<PendingDownload>, Error> {
+        let version = match self.version() {
+            Some(version) => version,
+            None => {
+                let latest = self.latest_version(&entries)?;
+                *self.version.lock().unwrap() = Some(latest.clone());
+                latest
+            }
+        };
+        let local = self.cache_path(workspace, &version)?;
+        if local.exists() {
+            info!("crate {} {} is already in cache", self.name, version);
+            crate::cache::touch(&local);
+            return Ok(None);
+        }
         if let Some(parent) = local.parent() {
             std::fs::create_dir_all(parent)?;
         }
-        let remote = format!(
-            "{0}/{1}/{1}-{2}.crate",
-            CRATES_ROOT, self.name, self.version
-        );
-        let mut resp = workspace
-            .http_client()
-            .get(&remote)
-            .send()?
-            .error_for_status()?;
-        resp.copy_to(&mut BufWriter::new(File::create(&local)?))?;
 
-        Ok(())
+        let entry = index::find_entry(entries, &self.name, &version)?;
+        if entry.yanked {
+            if workspace.deny_yanked() {
+                return Err(PrepareError::YankedCrate.into());
+            }
+            warn!("crate {} {} was yanked", self.name, version);
+        }
+        Ok(Some(PendingDownload {
+            url: self.download_url(workspace, &version),
+            version,
+            local,
+            cksum: entry.cksum,
+        }))
     }
 
-    fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
-        let path = self.cache_path(workspace);
-        if path.exists() {
-            std::fs::remove_file(&path)?;
-        }
-        Ok(())
+    fn download<'a, P: FnMut(u64, Option<u64>) + 'a>(
+        &'a self,
+        workspace: &'a Workspace,
+        download: PendingDownload,
+        progress: Option<P>,
+    ) -> impl Future<Item = (), Error = Error> + 'a {
+        // Concurrent fetches of the same crate (in this or other processes) wait for the first
+        // one to finish downloading it, instead of downloading it twice.
+        let mut lock = download.local.clone().into_os_string();
+        lock.push(".lock");
+        crate::utils::file_lock_async(
+            Path::new(&lock),
+            &format!("download crate {} {}", self.name, download.version),
+            false,
+        )
+        .and_then(move |crate_lock| {
+            let PendingDownload {
+                version,
+                url,
+                local,
+                cksum,
+            } = download;
+            if local.exists() {
+                info!("crate {} {} was fetched concurrently", self.name, version);
+                return Either::A(future::ok(()));
+            }
+
+            info!("fetching crate {} {}...", self.name, version);
+            Either::B(
+                download_to(
+                    workspace,
+                    &url,
+                    workspace.crates_io_credentials(),
+                    &local,
+                    progress,
+                    Arc::new(move |downloaded| index::verify_checksum(downloaded, &cksum)),
+                )
+                .then(move |res| {
+                    drop(crate_lock);
+                    res
+                }),
+            )
+        })
     }
 
-    fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
-        let cached = self.cache_path(workspace);
-        let mut file = File::open(cached)?;
-        let mut tar = Archive::new(GzDecoder::new(BufReader::new(&mut file)));
+    pub(super) fn copy_source_to_with_progress(
+        &self,
+        workspace: &Workspace,
+        dest: &Path,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<(), Error> {
+        let version = self.resolved_version()?;
+        let cached = self.cache_path(workspace, &version)?;
 
         info!(
             "extracting crate {} {} into {}",
             self.name,
-            self.version,
+            version,
             dest.display()
         );
-        if let Err(err) = unpack_without_first_dir(&mut tar, dest) {
+        let options = UnpackOptions::new();
+        if let Err(err) = crate::archive::unpack_with_progress(&cached, dest, &options, progress) {
             let _ = remove_dir_all(dest);
             Err(err
                 .context(format!(
                     "unable to download {} version {}",
-                    self.name, self.version
+                    self.name, version
                 ))
                 .into())
         } else {
@@ -92,30 +294,107 @@ impl CrateTrait for CratesIOCrate {
     }
 }
 
+pub(super) struct CratesIOCrate {
+    name: String,
+    version: Mutex<Option<String>>,
+}
+
+impl CrateTrait for CratesIOCrate {
+    fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        self.fetch_with_progress(workspace, None)
+    }
+
+    fn is_cached(&self, workspace: &Workspace) -> bool {
+        match self.version() {
+            Some(version) => {
+                matches!(self.cache_path(workspace, &version), Ok(path) if path.exists())
+            }
+            None => false,
+        }
+    }
+
+    fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
+        let version = match self.version() {
+            Some(version) => version,
+            // The crate can't be in the cache if its version was never resolved.
+            None => return Ok(()),
+        };
+        let path = self.cache_path(workspace, &version)?;
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        self.copy_source_to_with_progress(workspace, dest, &mut |_, _| {})
+    }
+}
+
+/// Ensure the crate name follows the naming rules of crates.io: it must start with a letter, and
+/// only contain ASCII letters, numbers, `-` and `_`.
+pub(super) fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = name.len() <= MAX_NAME_LENGTH
+        && matches!(name.chars().next(), Some(c) if c.is_ascii_alphabetic())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        failure::bail!("invalid crate name: {:?}", name);
+    }
+    Ok(())
+}
+
+fn validate_version(name: &str, version: &str) -> Result<(), Error> {
+    if Version::parse(version).is_err() {
+        failure::bail!("invalid version of crate {}: {:?}", name, version);
+    }
+    Ok(())
+}
+
 impl std::fmt::Display for CratesIOCrate {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "crates.io crate {} {}", self.name, self.version)
+        if let Some(version) = self.version() {
+            write!(f, "crates.io crate {} {}", self.name, version)
+        } else {
+            write!(f, "crates.io crate {} (latest version)", self.name)
+        }
     }
 }
 
-fn unpack_without_first_dir<R: Read>(archive: &mut Archive<R>, path: &Path) -> Result<(), Error> {
-    let entries = archive.entries()?;
-    for entry in entries {
-        let mut entry = entry?;
-        let relpath = {
-            let path = entry.path();
-            let path = path?;
-            path.into_owned()
-        };
-        let mut components = relpath.components();
-        // Throw away the first path component
-        components.next();
-        let full_path = path.join(&components.as_path());
-        if let Some(parent) = full_path.parent() {
-            std::fs::create_dir_all(parent)?;
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_validate_name() {
+        for name in &[
+            "lazy_static",
+            "serde-json",
+            "a",
+            "Inflector",
+            "rustc-ap-syntax_pos",
+        ] {
+            assert!(super::validate_name(name).is_ok(), "{} is valid", name);
+        }
+        for name in &[
+            "",
+            "1foo",
+            "../foo",
+            "foo/bar",
+            "foo bar",
+            "föö",
+            &"a".repeat(65),
+        ] {
+            assert!(super::validate_name(name).is_err(), "{} is invalid", name);
         }
-        entry.unpack(&full_path)?;
     }
 
-    Ok(())
+    #[test]
+    fn test_validate_version() {
+        for version in &["1.0.0", "0.1.0-alpha.1", "1.2.3+build"] {
+            assert!(super::validate_version("foo", version).is_ok());
+        }
+        for version in &["", "1.0", "latest", "1.0.0/../../foo"] {
+            assert!(super::validate_version("foo", version).is_err());
+        }
+    }
 }
@@ -0,0 +1,268 @@
+use crate::utils::UrlCredentials;
+use crate::Workspace;
+use failure::Error;
+use futures::future::{self, Either, Loop};
+use futures::{Future, Stream};
+use log::warn;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Function checking whether a downloaded file is valid.
+pub(super) type VerifyFn = Arc<dyn Fn(&Path) -> Result<(), Error> + Send + Sync>;
+
+/// Callback receiving the number of bytes downloaded so far and, if the server provided it, the
+/// total size of the download.
+pub(super) type DownloadProgress<'a> = &'a mut dyn FnMut(u64, Option<u64>);
+
+/// Progress callback type for downloads not reporting their progress, which unlike
+/// `DownloadProgress` keeps the download future `Send`.
+pub(super) type NoProgress = fn(u64, Option<u64>);
+
+/// Stream the content of `url` into the `dest` file, without buffering all of it in memory. The
+/// returned future must be executed inside a Tokio runtime.
+///
+/// The content is written to a uniquely named temporary file next to `dest`, which is checked with
+/// `verify` and moved into place only once the download is complete and valid: `dest` is never
+/// left partially written, and concurrent downloads of the same file don't interfere.
+///
+/// Transient failures are retried with an exponential backoff, according to the workspace
+/// configuration. If `credentials` are provided they're sent with HTTP basic authentication.
+pub(super) fn download_to<P: FnMut(u64, Option<u64>)>(
+    workspace: &Workspace,
+    url: &str,
+    credentials: Option<&UrlCredentials>,
+    dest: &Path,
+    progress: Option<P>,
+    verify: VerifyFn,
+) -> impl Future<Item = (), Error = Error> {
+    download_with_client(
+        workspace.async_http_client().clone(),
+        workspace.download_attempts(),
+        workspace.download_retry_delay(),
+        url.into(),
+        credentials.cloned(),
+        dest.into(),
+        progress,
+        verify,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn download_with_client<P: FnMut(u64, Option<u64>)>(
+    client: reqwest::r#async::Client,
+    attempts: usize,
+    delay: Duration,
+    url: String,
+    credentials: Option<UrlCredentials>,
+    dest: PathBuf,
+    progress: Option<P>,
+    verify: VerifyFn,
+) -> impl Future<Item = (), Error = Error> {
+    // The callback is shared by all the attempts, which restart reporting from zero.
+    let progress = progress.map(|progress| Arc::new(Mutex::new(progress)));
+    future::loop_fn((1, delay), move |(attempt, delay)| {
+        let url = url.clone();
+        download_once(
+            &client,
+            &url,
+            credentials.as_ref(),
+            &dest,
+            progress.clone(),
+            verify.clone(),
+        )
+        .then(move |res| match res {
+            Ok(()) => Either::A(future::ok(Loop::Break(()))),
+            Err(err) => {
+                if attempt >= attempts || !is_transient(&err) {
+                    return Either::A(future::err(err));
+                }
+                warn!(
+                    "failed to download {} (attempt {}/{}): {}",
+                    url, attempt, attempts, err
+                );
+                Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .from_err()
+                        .map(move |()| Loop::Continue((attempt + 1, delay * 2))),
+                )
+            }
+        })
+    })
+}
+
+fn download_once<P: FnMut(u64, Option<u64>)>(
+    client: &reqwest::r#async::Client,
+    url: &str,
+    credentials: Option<&UrlCredentials>,
+    dest: &Path,
+    progress: Option<Arc<Mutex<P>>>,
+    verify: VerifyFn,
+) -> impl Future<Item = (), Error = Error> {
+    let mut req = client.get(url);
+    if let Some((username, password)) = credentials {
+        req = req.basic_auth(username, password.as_ref());
+    }
+    // The temporary file is removed when it's dropped, unless it was moved into place.
+    let tmp = tempfile::Builder::new()
+        .prefix(".download")
+        .tempfile_in(dest.parent().unwrap_or_else(|| Path::new(".")));
+    let dest = dest.to_path_buf();
+
+    future::result(tmp).from_err().and_then(move |tmp| {
+        req.send()
+            .and_then(|resp| resp.error_for_status())
+            .from_err()
+            .and_then(move |resp| {
+                let total = resp.content_length();
+                resp.into_body().from_err().fold(
+                    (tmp, 0),
+                    move |(mut tmp, downloaded), chunk| -> Result<_, Error> {
+                        tmp.write_all(&chunk)?;
+                        let downloaded = downloaded + chunk.len() as u64;
+                        if let Some(progress) = &progress {
+                            (*progress.lock().unwrap())(downloaded, total);
+                        }
+                        Ok((tmp, downloaded))
+                    },
+                )
+            })
+            .and_then(move |(tmp, _)| {
+                verify(tmp.path())?;
+                tmp.persist(&dest)?;
+                Ok(())
+            })
+    })
+}
+
+fn is_transient(err: &Error) -> bool {
+    if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+        if let Some(status) = err.status() {
+            status.is_server_error()
+        } else {
+            err.is_http() || err.is_timeout()
+        }
+    } else if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        // Errors writing to the local file (like a full disk) shouldn't be retried.
+        matches!(
+            err.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+        )
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NoProgress, VerifyFn};
+    use failure::Error;
+    use std::io::{BufRead, BufReader, Error as IoError, ErrorKind, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Serve each of the provided raw HTTP responses to one connection, returning the URL.
+    fn serve(responses: Vec<&'static [u8]>) -> Result<String, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/foo-1.0.0.crate", listener.local_addr()?);
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                stream.write_all(response).unwrap();
+            }
+        });
+        Ok(url)
+    }
+
+    #[test]
+    fn test_download_partial_write() -> Result<(), Error> {
+        let url = serve(vec![
+            // The connection is closed before the whole body is sent.
+            b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\npartial",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\ncomplete",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\ninvalid",
+        ])?;
+        let client = reqwest::r#async::Client::builder().no_proxy().build()?;
+        let tmp = tempfile::tempdir()?;
+        let dest = tmp.path().join("foo-1.0.0.crate");
+        let download = |verify: VerifyFn| {
+            crate::utils::block_on(super::download_with_client(
+                client.clone(),
+                1,
+                Duration::from_secs(0),
+                url.clone(),
+                None,
+                dest.clone(),
+                None::<NoProgress>,
+                verify,
+            ))
+        };
+
+        assert!(download(Arc::new(|_| Ok(()))).is_err());
+        assert!(!dest.exists());
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 0);
+
+        download(Arc::new(|_| Ok(())))?;
+        assert_eq!(std::fs::read(&dest)?, b"complete");
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+
+        std::fs::remove_file(&dest)?;
+        assert!(download(Arc::new(|_| failure::bail!("checksum mismatch"))).is_err());
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_retry() -> Result<(), Error> {
+        let url = serve(vec![
+            b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\ncomplete",
+        ])?;
+        let client = reqwest::r#async::Client::builder().no_proxy().build()?;
+        let tmp = tempfile::tempdir()?;
+        let dest = tmp.path().join("foo-1.0.0.crate");
+
+        let mut reported = Vec::new();
+        let download = super::download_with_client(
+            client,
+            2,
+            Duration::from_secs(0),
+            url,
+            None,
+            dest.clone(),
+            Some(|downloaded, total| reported.push((downloaded, total))),
+            Arc::new(|_| Ok(())),
+        );
+        crate::utils::block_on(download)?;
+
+        assert_eq!(std::fs::read(&dest)?, b"complete");
+        assert_eq!(reported, vec![(8, Some(8))]);
+        // The temporary file was moved into place.
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let transient = Error::from(IoError::new(ErrorKind::ConnectionReset, "reset"));
+        assert!(super::is_transient(&transient));
+
+        let permanent = Error::from(IoError::new(ErrorKind::PermissionDenied, "denied"));
+        assert!(!super::is_transient(&permanent));
+
+        assert!(!super::is_transient(&failure::err_msg("unrelated")));
+    }
+}
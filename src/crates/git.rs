@@ -1,11 +1,12 @@
 use super::CrateTrait;
-use crate::cmd::Command;
+use crate::cmd::{Command, ProcessLinesActions};
 use crate::prepare::PrepareError;
+use crate::utils::UrlCredentials;
 use crate::Workspace;
 use failure::{Error, ResultExt};
 use log::{info, warn};
 use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 const ENCODE_SET: AsciiSet = CONTROLS
     .add(b'/')
@@ -19,18 +20,80 @@ const ENCODE_SET: AsciiSet = CONTROLS
     .add(b'*')
     .add(b' ');
 
+const GIT_USERNAME_ENV: &str = "RUSTWIDE_GIT_USERNAME";
+const GIT_PASSWORD_ENV: &str = "RUSTWIDE_GIT_PASSWORD";
+
+pub(super) enum GitReference {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
 pub(super) struct GitRepo {
     url: String,
+    credentials: Option<UrlCredentials>,
+    depth: Option<u32>,
+    reference: Option<GitReference>,
+    submodules: bool,
+    path_in_repo: Option<PathBuf>,
 }
 
 impl GitRepo {
     pub(super) fn new(url: &str) -> Self {
-        Self { url: url.into() }
+        // The credentials are passed to git through a credential helper instead of the URL, to
+        // prevent them from being logged or stored in the cache.
+        let (url, credentials) = crate::utils::split_url_credentials(url);
+        Self {
+            url,
+            credentials,
+            depth: None,
+            reference: None,
+            submodules: false,
+            path_in_repo: None,
+        }
+    }
+
+    pub(super) fn set_depth(&mut self, depth: Option<u32>) {
+        self.depth = depth;
+    }
+
+    pub(super) fn set_reference(&mut self, reference: GitReference) {
+        self.reference = Some(reference);
+    }
+
+    pub(super) fn set_submodules(&mut self, submodules: bool) {
+        self.submodules = submodules;
+    }
+
+    pub(super) fn set_path_in_repo(&mut self, path: &Path) {
+        self.path_in_repo = Some(path.into());
+    }
+
+    /// Revision to pass to `git rev-parse` to get the commit selected by the user.
+    fn rev_spec(&self) -> String {
+        match &self.reference {
+            None => "HEAD".into(),
+            Some(GitReference::Branch(name)) => format!("refs/heads/{}^{{commit}}", name),
+            Some(GitReference::Tag(name)) => format!("refs/tags/{}^{{commit}}", name),
+            Some(GitReference::Rev(rev)) => format!("{}^{{commit}}", rev),
+        }
+    }
+
+    fn depth_args(&self, path: &Path) -> Vec<String> {
+        if let Some(depth) = self.depth {
+            vec!["--depth".into(), depth.to_string()]
+        } else if path.join("shallow").is_file() {
+            // The cached repository was previously fetched with a limited depth.
+            vec!["--unshallow".into()]
+        } else {
+            Vec::new()
+        }
     }
 
     pub(super) fn git_commit(&self, workspace: &Workspace) -> Option<String> {
-        let res = Command::new(workspace, "git")
-            .args(&["rev-parse", "HEAD"])
+        let rev = self.rev_spec();
+        let res = Command::new(workspace, workspace.git())
+            .args(&["rev-parse", "--verify", &rev])
             .cd(&self.cached_path(workspace))
             .run_capture();
 
@@ -41,7 +104,7 @@ impl GitRepo {
                         return Some(shaline.to_string());
                     }
                 }
-                warn!("bad output from `git rev-parse HEAD`");
+                warn!("bad output from `git rev-parse --verify {}`", rev);
             }
             Err(e) => {
                 warn!("unable to capture sha for {}: {}", self.url, e);
@@ -50,6 +113,10 @@ impl GitRepo {
         None
     }
 
+    pub(super) fn url(&self) -> &str {
+        &self.url
+    }
+
     fn cached_path(&self, workspace: &Workspace) -> PathBuf {
         workspace
             .cache_dir()
@@ -57,11 +124,27 @@ impl GitRepo {
             .join(percent_encode(self.url.as_bytes(), &ENCODE_SET).to_string())
     }
 
-    fn suppress_password_prompt_args(&self, workspace: &Workspace) -> Vec<String> {
+    /// Create a git command that doesn't prompt for passwords, and that authenticates with the
+    /// credentials embedded in the repository URL, if any.
+    fn authenticated_git<'w>(&self, workspace: &'w Workspace) -> Command<'w, '_> {
         // The first `-c credential.helper=` clears the list of existing helpers
-        vec![
-            "-c".into(),
-            "credential.helper=".into(),
+        let mut cmd = Command::new(workspace, workspace.git()).args(&["-c", "credential.helper="]);
+        if let Some((username, password)) = &self.credentials {
+            // The helper is scoped to the repository URL, so that the credentials aren't sent to
+            // the hosts of its submodules. They're passed through the environment, as rustwide
+            // logs the command line of the commands it runs but not their environment.
+            cmd = cmd
+                .args(&[
+                    "-c".into(),
+                    format!(
+                        "credential.{}.helper=!f() {{ echo \"username=${{{}}}\"; echo \"password=${{{}}}\"; }}; f",
+                        self.url, GIT_USERNAME_ENV, GIT_PASSWORD_ENV
+                    ),
+                ])
+                .env(GIT_USERNAME_ENV, username)
+                .env(GIT_PASSWORD_ENV, password.as_deref().unwrap_or(""));
+        }
+        cmd.args(&[
             "-c".into(),
             format!(
                 "credential.helper={}",
@@ -71,73 +154,254 @@ impl GitRepo {
                     .unwrap()
                     .replace('\\', "/")
             ),
-        ]
+        ])
     }
-}
 
-impl CrateTrait for GitRepo {
-    fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
-        // The credential helper that suppresses the password prompt shows this message when a
-        // repository requires authentication:
-        //
-        //    fata: credential helper '{path}' told us to quit
-        //
-        let mut private_repository = false;
-        let mut detect_private_repositories = |line: &str| {
-            if line.starts_with("fatal: credential helper") && line.ends_with("told us to quit") {
-                private_repository = true;
+    fn checkout(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        if self.reference.is_none() {
+            Command::new(workspace, workspace.git())
+                .args(&["clone"])
+                .args(&[self.cached_path(workspace).as_path(), dest])
+                .run()
+                .with_context(|_| format!("failed to checkout {}", self.url))?;
+        } else {
+            let commit = self.git_commit(workspace).ok_or_else(|| {
+                failure::format_err!(
+                    "failed to find revision {} in {}",
+                    self.rev_spec(),
+                    self.url
+                )
+            })?;
+            Command::new(workspace, workspace.git())
+                .args(&["clone", "--no-checkout"])
+                .args(&[self.cached_path(workspace).as_path(), dest])
+                .run()
+                .with_context(|_| format!("failed to checkout {}", self.url))?;
+            Command::new(workspace, workspace.git())
+                .args(&["checkout", "--detach", &commit])
+                .cd(dest)
+                .run()
+                .with_context(|_| format!("failed to checkout {} at {}", self.url, commit))?;
+        }
+
+        if self.submodules {
+            self.update_submodules(workspace, dest)?;
+        }
+        Ok(())
+    }
+
+    fn update_submodules(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        info!("fetching submodules of repository {}", self.url);
+        // The checkout is cloned from the cached repository, so relative submodule URLs have to
+        // be resolved against the upstream URL instead.
+        self.authenticated_git(workspace)
+            .args(&["-c", &format!("remote.origin.url={}", self.url)])
+            .args(&["submodule", "update", "--init", "--recursive"])
+            .cd(dest)
+            .run()
+            .with_context(|_| format!("failed to fetch the submodules of {}", self.url))?;
+        Ok(())
+    }
+
+    fn fetch_locked(&self, workspace: &Workspace, path: &Path) -> Result<(), Error> {
+        let mut failure_cause = None;
+        let mut detect_failure_cause = |line: &str, _actions: &mut ProcessLinesActions| {
+            if let Some(cause) = git_failure_cause(line) {
+                failure_cause = Some(cause);
             }
         };
 
-        let path = self.cached_path(workspace);
         let res = if path.join("HEAD").is_file() {
             info!("updating cached repository {}", self.url);
-            Command::new(workspace, "git")
-                .args(&self.suppress_password_prompt_args(workspace))
+            self.authenticated_git(workspace)
                 .args(&["-c", "remote.origin.fetch=refs/heads/*:refs/heads/*"])
                 .args(&["fetch", "origin", "--force", "--prune"])
-                .cd(&path)
-                .process_lines(&mut detect_private_repositories)
+                .args(&self.depth_args(path))
+                .args(if let Some(GitReference::Tag(_)) = self.reference {
+                    &["--tags"][..]
+                } else {
+                    &[]
+                })
+                .cd(path)
+                .process_lines(&mut detect_failure_cause)
                 .run()
                 .with_context(|_| format!("failed to update {}", self.url))
         } else {
             info!("cloning repository {}", self.url);
-            Command::new(workspace, "git")
-                .args(&self.suppress_password_prompt_args(workspace))
+            self.authenticated_git(workspace)
                 .args(&["clone", "--bare", &self.url])
-                .args(&[&path])
-                .process_lines(&mut detect_private_repositories)
+                .args(&self.depth_args(path))
+                .args(&[path])
+                .process_lines(&mut detect_failure_cause)
                 .run()
                 .with_context(|_| format!("failed to clone {}", self.url))
         };
 
-        if private_repository && res.is_err() {
-            Err(PrepareError::PrivateGitRepository.into())
-        } else {
-            Ok(res?)
+        match (res, failure_cause) {
+            (Err(_), Some(cause)) => Err(cause.into()),
+            (res, _) => Ok(res?),
         }
     }
+}
+
+impl CrateTrait for GitRepo {
+    fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
+        let path = self.cached_path(workspace);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        // Concurrent fetches of the same repository (in this or other processes) are serialized,
+        // as git doesn't support updating the same repository concurrently.
+        let mut lock = path.clone().into_os_string();
+        lock.push(".lock");
+        crate::utils::file_lock(
+            Path::new(&lock),
+            &format!("fetch repository {}", self.url),
+            std::panic::AssertUnwindSafe(|| self.fetch_locked(workspace, &path)),
+        )
+    }
+
+    fn is_cached(&self, workspace: &Workspace) -> bool {
+        self.cached_path(workspace).join("HEAD").is_file()
+    }
 
     fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error> {
         let path = self.cached_path(workspace);
-        if path.exists() {
-            remove_dir_all::remove_dir_all(&path)?;
+        if !path.exists() {
+            return Ok(());
         }
-        Ok(())
+
+        // Wait for concurrent fetches of the repository to finish before removing it.
+        let mut lock = path.clone().into_os_string();
+        lock.push(".lock");
+        crate::utils::file_lock(
+            Path::new(&lock),
+            &format!("purge repository {}", self.url),
+            || {
+                if path.exists() {
+                    remove_dir_all::remove_dir_all(&path)?;
+                }
+                Ok(())
+            },
+        )
     }
 
     fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
-        Command::new(workspace, "git")
-            .args(&["clone"])
-            .args(&[self.cached_path(workspace).as_path(), dest])
-            .run()
-            .with_context(|_| format!("failed to checkout {}", self.url))?;
+        let subdir = match &self.path_in_repo {
+            Some(subdir) => subdir,
+            None => return self.checkout(workspace, dest),
+        };
+
+        if !subdir
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            failure::bail!(
+                "path {} in {} must be relative and can't contain `..`",
+                subdir.display(),
+                self.url
+            );
+        }
+
+        // Checkout the whole repository next to the destination (so that the subdirectory can
+        // be moved instead of copied), and then discard everything else.
+        let parent = dest.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(parent)?;
+        let checkout = tempfile::Builder::new()
+            .prefix(".git-checkout-")
+            .tempdir_in(parent)?;
+        self.checkout(workspace, checkout.path())?;
+
+        let source = checkout.path().join(subdir);
+        if !source.is_dir() {
+            failure::bail!("path {} doesn't exist in {}", subdir.display(), self.url);
+        }
+        info!(
+            "copying {} from repository {} to {}",
+            subdir.display(),
+            self.url,
+            dest.display()
+        );
+        std::fs::rename(&source, dest)?;
         Ok(())
     }
 }
 
+/// Detect why git failed to clone or fetch a repository from a line of its output.
+///
+/// The credential helper that suppresses the password prompt shows this message when a
+/// repository requires authentication:
+///
+///    fatal: credential helper '{path}' told us to quit
+///
+/// while git shows this message if it would have prompted for credentials on the terminal:
+///
+///    fatal: could not read Username for '{url}': terminal prompts disabled
+///
+fn git_failure_cause(line: &str) -> Option<PrepareError> {
+    if (line.starts_with("fatal: credential helper") && line.ends_with("told us to quit"))
+        || (line.starts_with("fatal: could not read")
+            && line.ends_with("terminal prompts disabled"))
+    {
+        Some(PrepareError::PrivateGitRepository)
+    } else if (line.starts_with("fatal: repository") && line.ends_with("not found"))
+        || line.starts_with("ERROR: Repository not found")
+        || line.ends_with("does not appear to be a git repository")
+    {
+        Some(PrepareError::GitRepositoryNotFound)
+    } else {
+        None
+    }
+}
+
 impl std::fmt::Display for GitRepo {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "git repo {}", self.url)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::prepare::PrepareError;
+    use failure::Error;
+
+    #[test]
+    fn test_git_failure_cause() {
+        assert!(matches!(
+            super::git_failure_cause(
+                "fatal: credential helper '/cargo-home/bin/git-credential-null' told us to quit"
+            ),
+            Some(PrepareError::PrivateGitRepository)
+        ));
+        assert!(matches!(
+            super::git_failure_cause(
+                "fatal: could not read Username for 'https://github.com': terminal prompts disabled"
+            ),
+            Some(PrepareError::PrivateGitRepository)
+        ));
+        assert!(matches!(
+            super::git_failure_cause("fatal: repository 'https://example.com/foo/bar/' not found"),
+            Some(PrepareError::GitRepositoryNotFound)
+        ));
+        assert!(matches!(
+            super::git_failure_cause("ERROR: Repository not found."),
+            Some(PrepareError::GitRepositoryNotFound)
+        ));
+        assert!(super::git_failure_cause("remote: Counting objects: 100% (10/10)").is_none());
+    }
+
+    #[test]
+    fn test_depth_args() -> Result<(), Error> {
+        let cached = tempfile::tempdir()?;
+        let mut repo = super::GitRepo::new("https://example.com/foo");
+        assert!(repo.depth_args(cached.path()).is_empty());
+
+        repo.set_depth(Some(1));
+        assert_eq!(repo.depth_args(cached.path()), vec!["--depth", "1"]);
+
+        // Fetching a shallow repository without a depth fetches the rest of its history.
+        std::fs::write(cached.path().join("shallow"), b"")?;
+        repo.set_depth(None);
+        assert_eq!(repo.depth_args(cached.path()), vec!["--unshallow"]);
+        Ok(())
+    }
+}
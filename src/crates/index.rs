@@ -0,0 +1,304 @@
+use crate::prepare::PrepareError;
+use crate::Workspace;
+use failure::Error;
+use futures::Future;
+use log::info;
+use reqwest::StatusCode;
+use semver::Version;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+pub(super) static CRATES_IO_INDEX: &str = "https://index.crates.io";
+
+/// Entry of a crate version in the registry index.
+#[derive(Deserialize)]
+pub(super) struct IndexEntry {
+    pub(super) vers: String,
+    pub(super) cksum: String,
+    #[serde(default)]
+    pub(super) yanked: bool,
+}
+
+/// Fetch all the entries of a crate from the crates.io index, using the sparse HTTP protocol.
+pub(super) fn crates_io_entries(
+    workspace: &Workspace,
+    name: &str,
+) -> Result<Vec<IndexEntry>, Error> {
+    crate::utils::block_on(crates_io_entries_async(workspace, name))
+}
+
+/// Like `crates_io_entries`, but returning a future that must be executed inside a Tokio runtime.
+pub(super) fn crates_io_entries_async(
+    workspace: &Workspace,
+    name: &str,
+) -> impl Future<Item = Vec<IndexEntry>, Error = Error> + Send {
+    sparse_entries(workspace, CRATES_IO_INDEX, name)
+}
+
+/// Location of a registry index.
+#[derive(Debug, PartialEq)]
+enum IndexLocation<'a> {
+    /// Index fetched with the sparse HTTP protocol.
+    Sparse(&'a str),
+    /// Local checkout of a git index.
+    Local(&'a Path),
+}
+
+impl<'a> IndexLocation<'a> {
+    /// URLs starting with `sparse+` are fetched with the sparse HTTP protocol, while paths and
+    /// `file://` URLs are treated as local checkouts of a git index. Remote git indexes are not
+    /// supported, as rustwide doesn't clone them.
+    fn parse(index: &'a str) -> Result<Self, Error> {
+        if index.starts_with("sparse+") {
+            Ok(IndexLocation::Sparse(index.trim_start_matches("sparse+")))
+        } else if index.starts_with("file://") {
+            Ok(IndexLocation::Local(Path::new(
+                index.trim_start_matches("file://"),
+            )))
+        } else if index.contains("://") {
+            failure::bail!(
+                "fetching the git index {} is not supported: use the `sparse+` URL of the \
+                 registry or the path of a local checkout of the index",
+                index
+            );
+        } else {
+            Ok(IndexLocation::Local(Path::new(index)))
+        }
+    }
+}
+
+/// Fetch all the entries of a crate from a registry index, either with the sparse HTTP protocol
+/// or from a local checkout of a git index.
+pub(super) fn registry_entries(
+    workspace: &Workspace,
+    index: &str,
+    name: &str,
+) -> Result<Vec<IndexEntry>, Error> {
+    match IndexLocation::parse(index)? {
+        IndexLocation::Sparse(url) => crate::utils::block_on(sparse_entries(workspace, url, name)),
+        IndexLocation::Local(path) => local_entries(path, name),
+    }
+}
+
+/// Fetch all the entries of a crate from an index using the sparse HTTP protocol, returning a
+/// future that must be executed inside a Tokio runtime.
+fn sparse_entries(
+    workspace: &Workspace,
+    index: &str,
+    name: &str,
+) -> impl Future<Item = Vec<IndexEntry>, Error = Error> + Send {
+    info!(
+        "fetching the index entries of crate {} from {}",
+        name, index
+    );
+    workspace
+        .async_http_client()
+        .get(&entries_url(index, name))
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|mut resp| resp.text())
+        .map_err(registry_error)
+        .and_then(|content| parse_entries(&content))
+}
+
+/// Read all the entries of a crate from a local checkout of a git index.
+fn local_entries(index: &Path, name: &str) -> Result<Vec<IndexEntry>, Error> {
+    info!(
+        "reading the index entries of crate {} from {}",
+        name,
+        index.display()
+    );
+    match std::fs::read_to_string(index.join(index_path(name))) {
+        Ok(content) => parse_entries(&content),
+        Err(err) if err.kind() == ErrorKind::NotFound => Err(PrepareError::CrateNotFound.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The sparse index returns 404 for crates that don't exist.
+fn registry_error(err: reqwest::Error) -> Error {
+    if err.status() == Some(StatusCode::NOT_FOUND) {
+        PrepareError::CrateNotFound.into()
+    } else {
+        err.into()
+    }
+}
+
+fn entries_url(index: &str, name: &str) -> String {
+    format!("{}/{}", index.trim_end_matches('/'), index_path(name))
+}
+
+fn parse_entries(content: &str) -> Result<Vec<IndexEntry>, Error> {
+    let mut entries = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        entries.push(serde_json::from_str(line)?);
+    }
+    Ok(entries)
+}
+
+/// Fetch the entry of a specific crate version from the crates.io index.
+pub(super) fn crates_io_entry(
+    workspace: &Workspace,
+    name: &str,
+    version: &str,
+) -> Result<IndexEntry, Error> {
+    find_entry(crates_io_entries(workspace, name)?, name, version)
+}
+
+/// Find the entry of a specific crate version among the entries of the crate.
+pub(super) fn find_entry(
+    entries: Vec<IndexEntry>,
+    name: &str,
+    version: &str,
+) -> Result<IndexEntry, Error> {
+    entries
+        .into_iter()
+        .find(|entry| entry.vers == version)
+        .ok_or_else(|| {
+            info!("crate {} {} is missing from the index", name, version);
+            PrepareError::CrateNotFound.into()
+        })
+}
+
+/// Return the highest version among the entries that wasn't yanked, ignoring pre-releases.
+pub(super) fn latest_version(entries: &[IndexEntry]) -> Option<String> {
+    entries
+        .iter()
+        .filter(|entry| !entry.yanked)
+        .filter_map(|entry| Version::parse(&entry.vers).ok())
+        .filter(|version| !version.is_prerelease())
+        .max()
+        .map(|version| version.to_string())
+}
+
+/// Ensure the sha256 of the file at `path` matches the checksum recorded in the index.
+pub(super) fn verify_checksum(path: &Path, expected: &str) -> Result<(), Error> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    let actual = format!("{:x}", hasher.result());
+    if actual != expected.to_lowercase() {
+        return Err(PrepareError::ChecksumMismatch.into());
+    }
+    Ok(())
+}
+
+/// Path of the index file for a crate, relative to the root of the index.
+fn index_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{}", name),
+        2 => format!("2/{}", name),
+        3 => format!("3/{}/{}", &name[..1], name),
+        _ => format!("{}/{}/{}", &name[..2], &name[2..4], name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prepare::PrepareError;
+    use failure::Error;
+
+    #[test]
+    fn test_verify_checksum() -> Result<(), Error> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("foo-1.0.0.crate");
+        std::fs::write(&path, b"Hello world")?;
+
+        super::verify_checksum(
+            &path,
+            "64ec88ca00b268e5ba1a35678a1b5316d212f4f366b2477232534a8aeca37f3c",
+        )?;
+        let err = super::verify_checksum(&path, &"0".repeat(64)).unwrap_err();
+        assert!(err.downcast_ref::<PrepareError>().is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_latest_version() -> Result<(), Error> {
+        let entries = [
+            r#"{"name":"foo","vers":"0.9.0","cksum":"","yanked":false}"#,
+            r#"{"name":"foo","vers":"1.10.0","cksum":"","yanked":false}"#,
+            r#"{"name":"foo","vers":"1.2.0","cksum":"","yanked":false}"#,
+            r#"{"name":"foo","vers":"2.0.0","cksum":"","yanked":true}"#,
+            r#"{"name":"foo","vers":"3.0.0-beta.1","cksum":"","yanked":false}"#,
+        ]
+        .iter()
+        .map(|line| serde_json::from_str(line))
+        .collect::<Result<Vec<super::IndexEntry>, _>>()?;
+        assert_eq!(super::latest_version(&entries).as_deref(), Some("1.10.0"));
+
+        // Crates with only yanked versions don't have a latest version
+        assert!(super::latest_version(&entries[3..4]).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_entries() -> Result<(), Error> {
+        let index = tempfile::tempdir()?;
+        std::fs::create_dir_all(index.path().join("3").join("f"))?;
+        std::fs::write(
+            index.path().join("3").join("f").join("foo"),
+            concat!(
+                r#"{"name":"foo","vers":"0.1.0","cksum":"","yanked":true}"#,
+                "\n",
+                r#"{"name":"foo","vers":"0.2.0","cksum":""}"#,
+                "\n",
+            ),
+        )?;
+
+        let entries = super::local_entries(index.path(), "foo")?;
+        let versions = entries
+            .iter()
+            .map(|entry| (entry.vers.as_str(), entry.yanked))
+            .collect::<Vec<_>>();
+        assert_eq!(versions, vec![("0.1.0", true), ("0.2.0", false)]);
+
+        let err = match super::local_entries(index.path(), "bar") {
+            Ok(_) => panic!("crate bar should not be in the index"),
+            Err(err) => err,
+        };
+        assert!(matches!(
+            err.downcast_ref::<PrepareError>(),
+            Some(PrepareError::CrateNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_location() {
+        use super::IndexLocation;
+        use std::path::Path;
+
+        assert_eq!(
+            IndexLocation::parse("sparse+https://index.example.com/").unwrap(),
+            IndexLocation::Sparse("https://index.example.com/")
+        );
+        assert_eq!(
+            IndexLocation::parse("file:///opt/index").unwrap(),
+            IndexLocation::Local(Path::new("/opt/index"))
+        );
+        assert_eq!(
+            IndexLocation::parse("/opt/index").unwrap(),
+            IndexLocation::Local(Path::new("/opt/index"))
+        );
+        for git in &[
+            "https://github.com/rust-lang/crates.io-index",
+            "ssh://git@example.com/index.git",
+            "git://example.com/index.git",
+        ] {
+            assert!(IndexLocation::parse(git).is_err());
+        }
+    }
+
+    #[test]
+    fn test_index_path() {
+        assert_eq!(super::index_path("a"), "1/a");
+        assert_eq!(super::index_path("ab"), "2/ab");
+        assert_eq!(super::index_path("abc"), "3/a/abc");
+        assert_eq!(super::index_path("Serde"), "se/rd/serde");
+        assert_eq!(super::index_path("lazy_static"), "la/zy/lazy_static");
+    }
+}
@@ -1,17 +1,90 @@
 use super::CrateTrait;
+use crate::cmd::Command;
 use crate::Workspace;
 use failure::Error;
-use log::info;
+use log::{debug, info};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 pub(super) struct Local {
     path: PathBuf,
+    exclude_ignored: bool,
+    hardlink: bool,
+    prepared: bool,
 }
 
 impl Local {
     pub(super) fn new(path: &Path) -> Self {
-        Local { path: path.into() }
+        Local {
+            path: path.into(),
+            exclude_ignored: false,
+            hardlink: false,
+            prepared: false,
+        }
+    }
+
+    /// Create a local crate whose directory is used as-is, without any git-specific handling.
+    pub(super) fn prepared(path: &Path) -> Self {
+        Local {
+            path: path.into(),
+            exclude_ignored: false,
+            hardlink: false,
+            prepared: true,
+        }
+    }
+
+    pub(super) fn set_exclude_ignored(&mut self, exclude_ignored: bool) {
+        self.exclude_ignored = exclude_ignored;
+    }
+
+    pub(super) fn set_hardlink(&mut self, hardlink: bool) {
+        self.hardlink = hardlink;
+    }
+
+    /// List the files and directories ignored by git in the crate's directory, relative to it.
+    /// If the directory isn't inside a git repository nothing is ignored.
+    fn ignored_paths(&self, workspace: &Workspace) -> HashSet<PathBuf> {
+        let res = Command::new(workspace, workspace.git())
+            .args(&["-c", "core.quotePath=false"])
+            .args(&["ls-files", "--others", "--ignored", "--exclude-standard"])
+            .args(&["--directory"])
+            .cd(&self.path)
+            .log_output(false)
+            .run_capture();
+
+        match res {
+            Ok(out) => out
+                .stdout_lines()
+                .iter()
+                .map(|line| PathBuf::from(line.trim_end_matches('/')))
+                .collect(),
+            Err(_) => {
+                info!(
+                    "{} is not inside a git repository, not excluding ignored files",
+                    self.path.display()
+                );
+                HashSet::new()
+            }
+        }
+    }
+
+    /// Return the commit checked out in the crate's directory, or `None` if the directory isn't
+    /// inside a git repository.
+    pub(super) fn git_commit(&self, workspace: &Workspace) -> Option<String> {
+        if self.prepared {
+            return None;
+        }
+        let out = Command::new(workspace, workspace.git())
+            .args(&["rev-parse", "--verify", "HEAD"])
+            .cd(&self.path)
+            .log_output(false)
+            .run_capture()
+            .ok()?;
+        out.stdout_lines()
+            .first()
+            .filter(|line| !line.is_empty())
+            .cloned()
     }
 }
 
@@ -21,29 +94,58 @@ impl CrateTrait for Local {
         Ok(())
     }
 
+    fn is_cached(&self, _workspace: &Workspace) -> bool {
+        // Local crates are never cached, but they're available as long as their directory exists.
+        self.path.is_dir()
+    }
+
     fn purge_from_cache(&self, _workspace: &Workspace) -> Result<(), Error> {
         // There is no cache to purge for a local crate.
         Ok(())
     }
 
-    fn copy_source_to(&self, _workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+    fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
+        if self.prepared {
+            info!(
+                "copying prepared crate from {} to {}",
+                self.path.display(),
+                dest.display()
+            );
+            return copy_prepared_dir(&self.path, dest, self.hardlink);
+        }
+
+        let ignored = if self.exclude_ignored {
+            self.ignored_paths(workspace)
+        } else {
+            HashSet::new()
+        };
+
         info!(
             "copying local crate from {} to {}",
             self.path.display(),
             dest.display()
         );
-        copy_dir(&self.path, dest)?;
+        copy_dir(&self.path, dest, &ignored, self.hardlink)?;
         Ok(())
     }
 }
 
 impl std::fmt::Display for Local {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "local crate {}", self.path.display())
+        if self.prepared {
+            write!(f, "prepared crate {}", self.path.display())
+        } else {
+            write!(f, "local crate {}", self.path.display())
+        }
     }
 }
 
-fn copy_dir(src: &Path, dest: &Path) -> Result<(), Error> {
+fn copy_dir(
+    src: &Path,
+    dest: &Path,
+    ignored: &HashSet<PathBuf>,
+    hardlink: bool,
+) -> Result<(), Error> {
     let src = crate::utils::normalize_path(src);
     let dest = crate::utils::normalize_path(dest);
 
@@ -58,24 +160,88 @@ fn copy_dir(src: &Path, dest: &Path) -> Result<(), Error> {
         }
         let path = components.as_path();
 
+        if ignored.contains(path) {
+            if entry.file_type().is_dir() {
+                entries.skip_current_dir();
+            }
+            continue;
+        }
+
         if entry.file_type().is_dir() {
-            // don't copy /target directory
-            if entry.file_name() == "target" && entry.depth() == 1 {
-                info!("ignoring top-level target directory {}", path.display());
+            // don't copy /target and /.git directories
+            if (entry.file_name() == "target" || entry.file_name() == ".git") && entry.depth() == 1
+            {
+                info!("ignoring top-level directory {}", path.display());
                 entries.skip_current_dir();
             } else {
                 std::fs::create_dir_all(dest.join(path))?;
             }
         } else {
-            std::fs::copy(src.join(path), dest.join(path))?;
+            copy_file(&entry, &dest.join(path), hardlink)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy all the contents of `src` to `dest`. Symbolic links are followed, but only if they point
+/// inside `src`.
+fn copy_prepared_dir(src: &Path, dest: &Path, hardlink: bool) -> Result<(), Error> {
+    let src = crate::utils::normalize_path(&std::fs::canonicalize(src)?);
+    let dest = crate::utils::normalize_path(dest);
+
+    for entry in WalkDir::new(&src).follow_links(true) {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(&src)?;
+
+        if entry.path_is_symlink() {
+            let target = crate::utils::normalize_path(&std::fs::canonicalize(entry.path())?);
+            if !target.starts_with(&src) {
+                failure::bail!(
+                    "symbolic link {} points outside of {}",
+                    entry.path().display(),
+                    src.display()
+                );
+            }
+        }
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(dest.join(path))?;
+        } else {
+            copy_file(&entry, &dest.join(path), hardlink)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file found while walking the source directory, hardlinking it instead if possible when
+/// `hardlink` is true.
+///
+/// The top-level `Cargo.toml` and `Cargo.lock` are always copied, as they're modified while
+/// preparing the build, and symbolic links are always copied, as hardlinking them would link to
+/// the symbolic link itself instead of its target.
+fn copy_file(entry: &DirEntry, dest: &Path, hardlink: bool) -> Result<(), Error> {
+    let modified = entry.depth() == 1
+        && (entry.file_name() == "Cargo.toml" || entry.file_name() == "Cargo.lock");
+    if hardlink && !modified && !entry.path_is_symlink() {
+        match std::fs::hard_link(entry.path(), dest) {
+            Ok(()) => return Ok(()),
+            // Files on another filesystem can't be hardlinked, fall back to copying them.
+            Err(err) => debug!(
+                "failed to hardlink {}, copying it instead: {}",
+                entry.path().display(),
+                err
+            ),
         }
     }
+    std::fs::copy(entry.path(), dest)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use failure::Error;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
 
     #[test]
     fn test_copy_dir() -> Result<(), Error> {
@@ -87,7 +253,7 @@ mod tests {
         std::fs::write(tmp_src.path().join("foo"), b"Hello world")?;
         std::fs::write(tmp_src.path().join("dir").join("bar"), b"Rustwide")?;
 
-        super::copy_dir(tmp_src.path(), tmp_dest.path())?;
+        super::copy_dir(tmp_src.path(), tmp_dest.path(), &HashSet::new(), false)?;
 
         assert_eq!(std::fs::read(tmp_dest.path().join("foo"))?, b"Hello world");
         assert_eq!(
@@ -108,13 +274,111 @@ mod tests {
         )?;
         println!("made subdirs and files");
 
-        super::copy_dir(src.path(), dest.path())?;
+        super::copy_dir(src.path(), dest.path(), &HashSet::new(), false)?;
         println!("copied");
 
         assert!(!dest.path().join("target").exists());
         Ok(())
     }
 
+    #[test]
+    fn test_no_copy_git() -> Result<(), Error> {
+        let (src, dest) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        std::fs::create_dir(src.path().join(".git"))?;
+        std::fs::write(
+            src.path().join(".git").join("HEAD"),
+            b"ref: refs/heads/master",
+        )?;
+
+        super::copy_dir(src.path(), dest.path(), &HashSet::new(), false)?;
+
+        assert!(!dest.path().join(".git").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_copy_ignored() -> Result<(), Error> {
+        let (src, dest) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        std::fs::create_dir_all(src.path().join("dir").join("ignored-dir"))?;
+        std::fs::write(src.path().join("dir").join("ignored-dir").join("foo"), b"")?;
+        std::fs::write(src.path().join("dir").join("ignored-file"), b"")?;
+        std::fs::write(src.path().join("dir").join("kept"), b"")?;
+
+        let mut ignored = HashSet::new();
+        ignored.insert(PathBuf::from("dir/ignored-dir"));
+        ignored.insert(PathBuf::from("dir/ignored-file"));
+        super::copy_dir(src.path(), dest.path(), &ignored, false)?;
+
+        assert!(!dest.path().join("dir").join("ignored-dir").exists());
+        assert!(!dest.path().join("dir").join("ignored-file").exists());
+        assert!(dest.path().join("dir").join("kept").exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_hardlink() -> Result<(), Error> {
+        use std::os::unix::fs::MetadataExt;
+
+        let (src, dest) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        std::fs::create_dir(src.path().join("src"))?;
+        std::fs::write(src.path().join("src").join("lib.rs"), b"")?;
+        std::fs::write(src.path().join("Cargo.toml"), b"")?;
+
+        super::copy_dir(src.path(), dest.path(), &HashSet::new(), true)?;
+
+        let inode = |path: &std::path::Path| -> Result<u64, Error> { Ok(path.metadata()?.ino()) };
+        assert_eq!(
+            inode(&src.path().join("src").join("lib.rs"))?,
+            inode(&dest.path().join("src").join("lib.rs"))?
+        );
+        assert_ne!(
+            inode(&src.path().join("Cargo.toml"))?,
+            inode(&dest.path().join("Cargo.toml"))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_prepared_dir() -> Result<(), Error> {
+        let (src, dest) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        std::fs::create_dir_all(src.path().join("target"))?;
+        std::fs::write(src.path().join("target").join("a.out"), b"")?;
+        std::fs::create_dir_all(src.path().join("dir"))?;
+        std::fs::write(src.path().join("dir").join("foo"), b"Hello world")?;
+
+        super::copy_prepared_dir(src.path(), dest.path(), false)?;
+        assert!(dest.path().join("target").join("a.out").exists());
+        assert_eq!(
+            std::fs::read(dest.path().join("dir").join("foo"))?,
+            b"Hello world"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_prepared_dir_symlinks() -> Result<(), Error> {
+        use std::os::unix::fs::symlink;
+
+        let (src, outside) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        std::fs::write(src.path().join("foo"), b"Hello world")?;
+        std::fs::write(outside.path().join("secret"), b"secret")?;
+
+        // Links inside the directory are followed
+        symlink("foo", src.path().join("link"))?;
+        let dest = tempfile::tempdir()?;
+        super::copy_prepared_dir(src.path(), dest.path(), false)?;
+        assert_eq!(std::fs::read(dest.path().join("link"))?, b"Hello world");
+
+        // Links pointing outside the directory are rejected
+        symlink(outside.path().join("secret"), src.path().join("secret"))?;
+        let dest = tempfile::tempdir()?;
+        assert!(super::copy_prepared_dir(src.path(), dest.path(), false).is_err());
+        assert!(!dest.path().join("secret").exists());
+        Ok(())
+    }
+
     #[test]
     fn test_copy_symlinks() -> Result<(), Error> {
         use std::{fs, os, path::Path};
@@ -122,7 +386,7 @@ mod tests {
         let tmp_src = tempfile::tempdir()?;
         let tmp_dest = tempfile::tempdir()?;
         let assert_copy_err_has_filename = || {
-            match super::copy_dir(tmp_src.path(), tmp_dest.path()) {
+            match super::copy_dir(tmp_src.path(), tmp_dest.path(), &HashSet::new(), false) {
                 Ok(_) => panic!("copy with bad symbolic link did not fail"),
                 Err(err) => assert!(err.downcast::<walkdir::Error>().unwrap().path().is_some()),
             };
@@ -147,7 +411,7 @@ mod tests {
 
         fs::remove_file(&bad_link)?;
         // make sure it works without that link
-        super::copy_dir(tmp_src.path(), tmp_dest.path())?;
+        super::copy_dir(tmp_src.path(), tmp_dest.path(), &HashSet::new(), false)?;
 
         // test link to self
         #[cfg(unix)]
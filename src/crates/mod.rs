@@ -1,15 +1,24 @@
 mod cratesio;
+mod download;
 mod git;
+mod index;
 mod local;
+mod report;
 
 use crate::Workspace;
 use failure::Error;
+use futures::{future, Future};
 use log::info;
 use remove_dir_all::remove_dir_all;
+use std::io::ErrorKind;
 use std::path::Path;
+use walkdir::WalkDir;
+
+pub use report::FetchReport;
 
 trait CrateTrait: std::fmt::Display {
     fn fetch(&self, workspace: &Workspace) -> Result<(), Error>;
+    fn is_cached(&self, workspace: &Workspace) -> bool;
     fn purge_from_cache(&self, workspace: &Workspace) -> Result<(), Error>;
     fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error>;
 }
@@ -20,6 +29,47 @@ enum CrateType {
     Local(local::Local),
 }
 
+/// Location and size of a crate's download, returned by
+/// [`Crate::download_info`](struct.Crate.html#method.download_info).
+#[derive(Debug, Clone)]
+pub struct DownloadInfo {
+    url: String,
+    size: Option<u64>,
+}
+
+impl DownloadInfo {
+    /// Return the URL the crate is downloaded from.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Return the size of the download in bytes, if it's known in advance.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+/// Version of a crate published in a registry, returned by
+/// [`Crate::crates_io_versions`](struct.Crate.html#method.crates_io_versions) and
+/// [`Crate::registry_versions`](struct.Crate.html#method.registry_versions).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrateVersion {
+    version: String,
+    yanked: bool,
+}
+
+impl CrateVersion {
+    /// Return the version number.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Return whether the version was yanked.
+    pub fn is_yanked(&self) -> bool {
+        self.yanked
+    }
+}
+
 /// A Rust crate that can be used with rustwide.
 pub struct Crate(CrateType);
 
@@ -31,21 +81,265 @@ impl Crate {
         )))
     }
 
+    /// Load the latest version of a crate from the [crates.io registry](https://crates.io).
+    ///
+    /// The version is resolved when the crate is fetched, by querying the crates.io index over
+    /// the network for the highest version that wasn't yanked (pre-releases are ignored). Fetching
+    /// a crate whose versions are all yanked returns an error. Once the crate is fetched the
+    /// resolved version can be retrieved with
+    /// [`resolved_version`](struct.Crate.html#method.resolved_version).
+    pub fn crates_io_latest(name: &str) -> Self {
+        Crate(CrateType::CratesIO(cratesio::CratesIOCrate::latest(name)))
+    }
+
+    /// Return all the versions of a crate published on the [crates.io registry](https://crates.io),
+    /// including the yanked ones, in the order they were published. This method reaches out to
+    /// the network to query the crates.io index.
+    pub fn crates_io_versions(
+        name: &str,
+        workspace: &Workspace,
+    ) -> Result<Vec<CrateVersion>, Error> {
+        Self::registry_versions(
+            name,
+            &format!("sparse+{}", index::CRATES_IO_INDEX),
+            workspace,
+        )
+    }
+
+    /// Return all the versions of a crate published on a registry, including the yanked ones, in
+    /// the order they were published.
+    ///
+    /// Indexes whose URL starts with `sparse+` are queried over the network with the sparse
+    /// protocol. Git indexes are not fetched by rustwide: for them the path (or `file://` URL) of
+    /// a local checkout of the index must be provided, and the crate's file is read from it. An
+    /// error is returned for the URLs of remote git indexes.
+    ///
+    /// [`PrepareError::CrateNotFound`](enum.PrepareError.html#variant.CrateNotFound) is returned
+    /// if the crate doesn't exist in the index.
+    pub fn registry_versions(
+        name: &str,
+        index: &str,
+        workspace: &Workspace,
+    ) -> Result<Vec<CrateVersion>, Error> {
+        // Validating the name ensures it can't escape the index.
+        cratesio::validate_name(name)?;
+        Ok(index::registry_entries(workspace, index, name)?
+            .into_iter()
+            .map(|entry| CrateVersion {
+                version: entry.vers,
+                yanked: entry.yanked,
+            })
+            .collect())
+    }
+
     /// Load a crate from a git repository. The full URL needed to clone the repo has to be
     /// provided.
     pub fn git(url: &str) -> Self {
         Crate(CrateType::Git(git::GitRepo::new(url)))
     }
 
+    /// Limit the history fetched for a git crate to the last `depth` commits, which speeds up
+    /// fetching large repositories. Passing `None` fetches the whole history, which is the
+    /// default. Cached repositories are deepened or unshallowed as needed the next time the crate
+    /// is fetched.
+    ///
+    /// This option only applies to crates created with [`git`](struct.Crate.html#method.git),
+    /// and it's ignored for other kinds of crates.
+    pub fn depth(mut self, depth: Option<u32>) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_depth(depth);
+        }
+        self
+    }
+
+    /// Use the tip of the provided branch of a git crate, instead of the remote's default branch.
+    ///
+    /// This option only applies to crates created with [`git`](struct.Crate.html#method.git),
+    /// and it's ignored for other kinds of crates. It replaces any previously selected tag or
+    /// revision.
+    pub fn branch(mut self, name: &str) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_reference(git::GitReference::Branch(name.into()));
+        }
+        self
+    }
+
+    /// Use the commit pointed to by the provided tag of a git crate, instead of the remote's
+    /// default branch.
+    ///
+    /// This option only applies to crates created with [`git`](struct.Crate.html#method.git),
+    /// and it's ignored for other kinds of crates. It replaces any previously selected branch or
+    /// revision.
+    pub fn tag(mut self, name: &str) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_reference(git::GitReference::Tag(name.into()));
+        }
+        self
+    }
+
+    /// Use the provided revision (for example a commit hash) of a git crate, instead of the
+    /// remote's default branch. The revision must be reachable from one of the branches of the
+    /// repository, and within the fetched history if [`depth`](struct.Crate.html#method.depth)
+    /// is used.
+    ///
+    /// This option only applies to crates created with [`git`](struct.Crate.html#method.git),
+    /// and it's ignored for other kinds of crates. It replaces any previously selected branch or
+    /// tag.
+    pub fn rev(mut self, rev: &str) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_reference(git::GitReference::Rev(rev.into()));
+        }
+        self
+    }
+
+    /// Initialize and fetch the submodules of a git crate (recursively) when its source is copied
+    /// into a build directory. Submodules are not fetched by default.
+    ///
+    /// Submodules are fetched from their upstream repositories every time the source is copied,
+    /// as they're not stored in the workspace cache.
+    ///
+    /// This option only applies to crates created with [`git`](struct.Crate.html#method.git),
+    /// and it's ignored for other kinds of crates.
+    pub fn submodules(mut self, submodules: bool) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_submodules(submodules);
+        }
+        self
+    }
+
+    /// Build the crate located in the provided subdirectory of a git repository, instead of the
+    /// one at the root of the repository. The path must be relative to the repository root.
+    ///
+    /// Only the contents of the subdirectory will be copied in the build directory, so the git
+    /// metadata and the rest of the repository won't be available during the build.
+    ///
+    /// This option only applies to crates created with [`git`](struct.Crate.html#method.git),
+    /// and it's ignored for other kinds of crates.
+    pub fn path_in_repo(mut self, path: &str) -> Self {
+        if let CrateType::Git(repo) = &mut self.0 {
+            repo.set_path_in_repo(Path::new(path));
+        }
+        self
+    }
+
     /// Load a crate from a directory in the local filesystem.
     pub fn local(path: &Path) -> Self {
         Crate(CrateType::Local(local::Local::new(path)))
     }
 
+    /// Load a crate from a directory in the local filesystem, whose contents are used as-is.
+    ///
+    /// Unlike [`local`](struct.Crate.html#method.local) crates, all the files in the directory
+    /// are copied (including the `target` and `.git` directories), and git is never used to
+    /// inspect the directory. Symbolic links are followed, but copying the crate fails if one of
+    /// them points outside the directory.
+    pub fn prepared(path: &Path) -> Self {
+        Crate(CrateType::Local(local::Local::prepared(path)))
+    }
+
+    /// Don't copy the files ignored by git (through `.gitignore` and the other git exclude files)
+    /// when copying the source of a local crate in a build directory. If the crate's directory
+    /// isn't inside a git repository this option does nothing.
+    ///
+    /// The top-level `target` and `.git` directories are never copied, regardless of this option.
+    ///
+    /// This option only applies to crates created with [`local`](struct.Crate.html#method.local),
+    /// and it's ignored for other kinds of crates.
+    pub fn exclude_ignored(mut self, exclude_ignored: bool) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_exclude_ignored(exclude_ignored);
+        }
+        self
+    }
+
+    /// Hardlink the files of a local crate into the build directory instead of copying them, when
+    /// the build directory is on the same filesystem as the crate (files are still copied
+    /// otherwise). This is way faster for large crates, but it's only safe for builds that don't
+    /// modify the crate's source files, as the changes would be visible in the original files.
+    ///
+    /// The top-level `Cargo.toml` and `Cargo.lock` are always copied, as rustwide and cargo
+    /// modify them while preparing the build.
+    ///
+    /// This option only applies to crates created with [`local`](struct.Crate.html#method.local)
+    /// or [`prepared`](struct.Crate.html#method.prepared), and it's ignored for other kinds of
+    /// crates.
+    pub fn hardlink(mut self, hardlink: bool) -> Self {
+        if let CrateType::Local(local) = &mut self.0 {
+            local.set_hardlink(hardlink);
+        }
+        self
+    }
+
     /// Fetch the crate's source code and cache it in the workspace. This method will reach out to
     /// the network for some crate types.
+    ///
+    /// Crates from crates.io are fetched by running
+    /// [`fetch_async`](struct.Crate.html#method.fetch_async) to completion, so this method must not
+    /// be called from inside a Tokio runtime.
     pub fn fetch(&self, workspace: &Workspace) -> Result<(), Error> {
-        self.as_trait().fetch(workspace)
+        workspace
+            .lock_cache(true, "purge the cache", || self.as_trait().fetch(workspace))
+            .map_err(|err| fs_error_context(err, &workspace.cache_dir()))
+    }
+
+    /// Asynchronous version of [`fetch`](struct.Crate.html#method.fetch), which returns a future
+    /// that must be executed inside a Tokio runtime.
+    ///
+    /// Crates from crates.io are fetched with an asynchronous HTTP client, allowing lots of them
+    /// to be fetched concurrently without a thread each. Other kinds of crates are fetched with
+    /// blocking operations when the future is polled, so they should be fetched with
+    /// [`fetch`](struct.Crate.html#method.fetch) instead.
+    ///
+    /// Like with [`fetch`](struct.Crate.html#method.fetch), concurrent fetches of the same
+    /// crates.io crate wait for the first one to finish downloading it, and purging the cache
+    /// waits for the downloads in progress. Waiting for those locks doesn't block the thread
+    /// running the future.
+    pub fn fetch_async<'a>(
+        &'a self,
+        workspace: &'a Workspace,
+    ) -> Box<dyn Future<Item = (), Error = Error> + Send + 'a> {
+        match &self.0 {
+            CrateType::CratesIO(krate) => Box::new(
+                krate
+                    .fetch_async(workspace)
+                    .map_err(move |err| fs_error_context(err, &workspace.cache_dir())),
+            ),
+            _ => Box::new(future::lazy(move || self.fetch(workspace))),
+        }
+    }
+
+    /// Fetch the crate's source code and cache it in the workspace, calling `progress` while the
+    /// crate is being downloaded with the number of bytes downloaded so far and, if known, the
+    /// total size of the download.
+    ///
+    /// Progress is currently reported just for crates.io crates: for other crate types this
+    /// method behaves like [`fetch`](struct.Crate.html#method.fetch).
+    pub fn fetch_with_progress(
+        &self,
+        workspace: &Workspace,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<(), Error> {
+        if let CrateType::CratesIO(krate) = &self.0 {
+            workspace
+                .lock_cache(true, "purge the cache", || {
+                    krate.fetch_with_progress(workspace, Some(progress))
+                })
+                .map_err(|err| fs_error_context(err, &workspace.cache_dir()))
+        } else {
+            self.fetch(workspace)
+        }
+    }
+
+    /// Check whether the crate is already in the workspace cache, without reaching out to the
+    /// network. Fetching a cached crate doesn't need to download it again, except for git crates
+    /// which are always updated.
+    ///
+    /// crates.io crates created with
+    /// [`crates_io_latest`](struct.Crate.html#method.crates_io_latest) are only considered cached
+    /// once their version is resolved by fetching them, and local crates are cached if their
+    /// directory exists.
+    pub fn is_cached(&self, workspace: &Workspace) -> bool {
+        self.as_trait().is_cached(workspace)
     }
 
     /// Remove the cached copy of this crate. The method will do nothing if the crate isn't cached.
@@ -54,24 +348,98 @@ impl Crate {
     }
 
     /// Get this crate's git commit. This method is best-effort, and currently works just for git
-    /// crates. If the commit can't be retrieved `None` will be returned.
+    /// crates and local crates inside a git repository. If the commit can't be retrieved `None`
+    /// will be returned.
+    ///
+    /// If a branch, tag or revision was selected for the crate, the commit it resolves to will be
+    /// returned.
     pub fn git_commit(&self, workspace: &Workspace) -> Option<String> {
-        if let CrateType::Git(repo) = &self.0 {
-            repo.git_commit(workspace)
+        match &self.0 {
+            CrateType::Git(repo) => repo.git_commit(workspace),
+            CrateType::Local(local) => local.git_commit(workspace),
+            CrateType::CratesIO(_) => None,
+        }
+    }
+
+    /// Check whether this crate's version was yanked from its registry. This method reaches out
+    /// to the network to query the registry index.
+    ///
+    /// Crates not loaded from a registry (like git or local crates) are never considered yanked.
+    pub fn is_yanked(&self, workspace: &Workspace) -> Result<bool, Error> {
+        if let CrateType::CratesIO(krate) = &self.0 {
+            krate.is_yanked(workspace)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Return where this crate is downloaded from and, if known, how big the download is, without
+    /// fetching the crate. This method might reach out to the network.
+    ///
+    /// For crates.io crates the latest version is resolved if needed, and the size is retrieved
+    /// from the server. For git crates the URL is the repository's one, and the size is not known.
+    /// Local crates are not downloaded, and an error is returned for them.
+    pub fn download_info(&self, workspace: &Workspace) -> Result<DownloadInfo, Error> {
+        match &self.0 {
+            CrateType::CratesIO(krate) => krate.download_info(workspace),
+            CrateType::Git(repo) => Ok(DownloadInfo {
+                url: repo.url().into(),
+                size: None,
+            }),
+            CrateType::Local(_) => failure::bail!("{} is not downloaded", self),
+        }
+    }
+
+    /// Get the version of this crate. This returns `None` for crates that are not loaded from
+    /// crates.io, and for crates created with
+    /// [`crates_io_latest`](struct.Crate.html#method.crates_io_latest) that weren't fetched yet.
+    pub fn resolved_version(&self) -> Option<String> {
+        if let CrateType::CratesIO(krate) = &self.0 {
+            krate.version()
         } else {
             None
         }
     }
 
-    pub(crate) fn copy_source_to(&self, workspace: &Workspace, dest: &Path) -> Result<(), Error> {
-        if dest.exists() {
+    pub(crate) fn copy_source_to(
+        &self,
+        workspace: &Workspace,
+        dest: &Path,
+        progress: Option<&(dyn Fn(u64, u64) + Send + Sync)>,
+        keep_existing: bool,
+    ) -> Result<(), Error> {
+        if dest.exists() && keep_existing {
+            info!(
+                "crate source directory {} already exists, copying the crate over it",
+                dest.display()
+            );
+            // Not all the crate types can be copied into a non-empty directory (git refuses to
+            // clone in it), so the source is copied next to the destination and then moved.
+            let parent = dest.parent().unwrap_or_else(|| Path::new("."));
+            let staging = tempfile::Builder::new()
+                .prefix(".source-")
+                .tempdir_in(parent)
+                .map_err(|err| fs_error_context(err.into(), parent))?;
+            let staged = staging.path().join("source");
+            self.copy_source_to(workspace, &staged, progress, false)?;
+            return merge_dir(&staged, dest).map_err(|err| fs_error_context(err, dest));
+        } else if dest.exists() {
             info!(
                 "crate source directory {} already exists, cleaning it up",
                 dest.display()
             );
             remove_dir_all(dest)?;
         }
-        self.as_trait().copy_source_to(workspace, dest)
+        workspace
+            .lock_cache(true, "purge the cache", || match (&self.0, progress) {
+                (CrateType::CratesIO(krate), Some(progress)) => {
+                    krate.copy_source_to_with_progress(workspace, dest, &mut |entries, bytes| {
+                        progress(entries, bytes)
+                    })
+                }
+                _ => self.as_trait().copy_source_to(workspace, dest),
+            })
+            .map_err(|err| fs_error_context(err, dest))
     }
 
     fn as_trait(&self) -> &dyn CrateTrait {
@@ -83,8 +451,113 @@ impl Crate {
     }
 }
 
+/// Move all the contents of `src` into `dest`, replacing the files with the same path.
+fn merge_dir(src: &Path, dest: &Path) -> Result<(), Error> {
+    // Collect the entries first, as they're moved away while the directory is walked otherwise.
+    let entries = WalkDir::new(src)
+        .min_depth(1)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    for entry in entries {
+        let target = dest.join(entry.path().strip_prefix(src)?);
+        let existing = std::fs::symlink_metadata(&target)
+            .ok()
+            .map(|meta| meta.file_type());
+        match (entry.file_type().is_dir(), existing) {
+            (true, Some(kind)) if kind.is_dir() => {}
+            (true, Some(_)) => {
+                std::fs::remove_file(&target)?;
+                std::fs::create_dir(&target)?;
+            }
+            (true, None) => std::fs::create_dir(&target)?,
+            (false, Some(kind)) if kind.is_dir() => {
+                remove_dir_all(&target)?;
+                std::fs::rename(entry.path(), &target)?;
+            }
+            (false, _) => std::fs::rename(entry.path(), &target)?,
+        }
+    }
+    Ok(())
+}
+
+/// Explain the likely cause of common filesystem errors, like running out of disk space, naming
+/// the path that was being written to. Other errors are returned unchanged.
+fn fs_error_context(err: Error, path: &Path) -> Error {
+    let kind = err
+        .iter_chain()
+        .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .map(|err| err.kind());
+    let msg = match kind {
+        Some(ErrorKind::StorageFull) | Some(ErrorKind::QuotaExceeded) => {
+            format!("failed to write to {}: the disk is full", path.display())
+        }
+        Some(ErrorKind::PermissionDenied) => format!(
+            "failed to write to {}: the directory is not writable by the current user",
+            path.display()
+        ),
+        _ => return err,
+    };
+    err.context(msg).into()
+}
+
 impl std::fmt::Display for Crate {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.as_trait())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use failure::{Error, ResultExt};
+    use std::io::{Error as IoError, ErrorKind};
+    use std::path::Path;
+
+    #[test]
+    fn test_merge_dir() -> Result<(), Error> {
+        let src = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        std::fs::create_dir(src.path().join("src"))?;
+        std::fs::write(src.path().join("Cargo.toml"), b"new")?;
+        std::fs::write(src.path().join("src").join("lib.rs"), b"new")?;
+        std::fs::create_dir(dest.path().join("vendor"))?;
+        std::fs::write(dest.path().join("Cargo.toml"), b"old")?;
+        std::fs::write(dest.path().join("vendor").join("dep.rs"), b"old")?;
+
+        super::merge_dir(src.path(), dest.path())?;
+
+        assert_eq!(std::fs::read(dest.path().join("Cargo.toml"))?, b"new");
+        assert_eq!(
+            std::fs::read(dest.path().join("src").join("lib.rs"))?,
+            b"new"
+        );
+        assert_eq!(
+            std::fs::read(dest.path().join("vendor").join("dep.rs"))?,
+            b"old"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_error_context() {
+        let path = Path::new("/cache");
+        let disk_full: Result<(), _> = Err(IoError::new(ErrorKind::StorageFull, "no space"));
+        let err = disk_full.context("unable to download foo").unwrap_err();
+        let err = super::fs_error_context(err.into(), path);
+        assert_eq!(
+            err.to_string(),
+            "failed to write to /cache: the disk is full"
+        );
+
+        let denied = Error::from(IoError::new(ErrorKind::PermissionDenied, "denied"));
+        assert_eq!(
+            super::fs_error_context(denied, path).to_string(),
+            "failed to write to /cache: the directory is not writable by the current user"
+        );
+
+        let other = failure::err_msg("unrelated");
+        assert_eq!(
+            super::fs_error_context(other, path).to_string(),
+            "unrelated"
+        );
+    }
+}
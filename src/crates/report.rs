@@ -0,0 +1,163 @@
+use crate::prepare::PrepareError;
+use failure::Error;
+use reqwest::StatusCode;
+use std::fmt;
+
+/// Summary of the outcome of fetching multiple crates, for example with
+/// [`Workspace::fetch_all`](struct.Workspace.html#method.fetch_all).
+///
+/// Failures are grouped by looking at the errors (and their causes) returned by the fetches:
+///
+/// * [`PrepareError::CrateNotFound`](enum.PrepareError.html#variant.CrateNotFound),
+///   [`PrepareError::GitRepositoryNotFound`](enum.PrepareError.html#variant.GitRepositoryNotFound)
+///   and HTTP 404 responses are counted as not found;
+/// * [`PrepareError::NetworkError`](enum.PrepareError.html#variant.NetworkError), connection
+///   failures, timeouts and HTTP 5xx responses are counted as network errors;
+/// * [`PrepareError::ChecksumMismatch`](enum.PrepareError.html#variant.ChecksumMismatch) is
+///   counted as a checksum failure;
+/// * everything else is counted as another error.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FetchReport {
+    succeeded: usize,
+    not_found: usize,
+    network_errors: usize,
+    checksum_failures: usize,
+    other_errors: usize,
+}
+
+impl FetchReport {
+    /// Create a report from the outcome of each fetch.
+    pub fn new(results: &[Result<(), Error>]) -> Self {
+        let mut report = FetchReport::default();
+        for result in results {
+            match result {
+                Ok(()) => report.succeeded += 1,
+                Err(err) => match categorize(err) {
+                    Category::NotFound => report.not_found += 1,
+                    Category::Network => report.network_errors += 1,
+                    Category::Checksum => report.checksum_failures += 1,
+                    Category::Other => report.other_errors += 1,
+                },
+            }
+        }
+        report
+    }
+
+    /// Return the number of crates fetched successfully.
+    pub fn succeeded(&self) -> usize {
+        self.succeeded
+    }
+
+    /// Return the number of crates (or crate versions) that don't exist.
+    pub fn not_found(&self) -> usize {
+        self.not_found
+    }
+
+    /// Return the number of fetches that failed due to a network error. Retrying them later might
+    /// succeed.
+    pub fn network_errors(&self) -> usize {
+        self.network_errors
+    }
+
+    /// Return the number of downloaded crates that didn't match the checksum in the index.
+    pub fn checksum_failures(&self) -> usize {
+        self.checksum_failures
+    }
+
+    /// Return the number of fetches that failed for any other reason.
+    pub fn other_errors(&self) -> usize {
+        self.other_errors
+    }
+
+    /// Return the number of failed fetches.
+    pub fn failed(&self) -> usize {
+        self.not_found + self.network_errors + self.checksum_failures + self.other_errors
+    }
+
+    /// Return the total number of fetches included in the report.
+    pub fn total(&self) -> usize {
+        self.succeeded + self.failed()
+    }
+}
+
+impl fmt::Display for FetchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "fetched {}, {} not found, {} network errors, {} checksum failures, {} other errors",
+            self.succeeded,
+            self.not_found,
+            self.network_errors,
+            self.checksum_failures,
+            self.other_errors
+        )
+    }
+}
+
+enum Category {
+    NotFound,
+    Network,
+    Checksum,
+    Other,
+}
+
+fn categorize(err: &Error) -> Category {
+    for cause in err.iter_chain() {
+        if let Some(err) = cause.downcast_ref::<PrepareError>() {
+            match err {
+                PrepareError::CrateNotFound | PrepareError::GitRepositoryNotFound => {
+                    return Category::NotFound
+                }
+                PrepareError::NetworkError => return Category::Network,
+                PrepareError::ChecksumMismatch => return Category::Checksum,
+                _ => {}
+            }
+        } else if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            match err.status() {
+                Some(StatusCode::NOT_FOUND) => return Category::NotFound,
+                Some(status) if status.is_server_error() => return Category::Network,
+                Some(_) => {}
+                None if err.is_http() || err.is_timeout() => return Category::Network,
+                None => {}
+            }
+        }
+    }
+    Category::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FetchReport;
+    use crate::prepare::PrepareError;
+    use failure::ResultExt;
+
+    #[test]
+    fn test_fetch_report() {
+        let wrapped: Result<(), PrepareError> = Err(PrepareError::ChecksumMismatch);
+        let results = vec![
+            Ok(()),
+            Ok(()),
+            Err(PrepareError::CrateNotFound.into()),
+            Err(PrepareError::GitRepositoryNotFound.into()),
+            Err(PrepareError::NetworkError.into()),
+            Err(wrapped
+                .with_context(|_| "failed to fetch foo")
+                .unwrap_err()
+                .into()),
+            Err(failure::err_msg("unrelated")),
+        ];
+
+        let report = FetchReport::new(&results);
+        assert_eq!(report.succeeded(), 2);
+        assert_eq!(report.not_found(), 2);
+        assert_eq!(report.network_errors(), 1);
+        assert_eq!(report.checksum_failures(), 1);
+        assert_eq!(report.other_errors(), 1);
+        assert_eq!(report.failed(), 5);
+        assert_eq!(report.total(), 7);
+        assert_eq!(
+            report.to_string(),
+            "fetched 2, 2 not found, 1 network errors, 1 checksum failures, 1 other errors"
+        );
+    }
+}
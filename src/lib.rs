@@ -18,22 +18,33 @@
 #[macro_use]
 extern crate toml;
 
+pub mod archive;
 mod build;
+mod cache;
+mod cargo_messages;
 pub mod cmd;
 mod crates;
 mod inside_docker;
 pub mod logging;
+mod metadata;
 mod native;
 mod prepare;
+mod snapshot;
 mod toolchain;
 mod tools;
 mod utils;
 mod workspace;
 
-pub use crate::build::{Build, BuildBuilder, BuildDirectory};
-pub use crate::crates::Crate;
+pub use crate::build::{Build, BuildBuilder, BuildDirectory, DependencyKind, Patch};
+pub use crate::cache::{CachePurgePolicy, CrateCacheLayout};
+pub use crate::cargo_messages::{
+    CargoFailure, CargoMessage, CompilerArtifact, CompilerMessage, Diagnostic, DiagnosticSpan,
+};
+pub use crate::crates::{Crate, CrateVersion, DownloadInfo, FetchReport};
+pub use crate::metadata::{PackageDependency, PackageMetadata};
 pub use crate::prepare::PrepareError;
-pub use crate::toolchain::Toolchain;
-pub use crate::workspace::{Workspace, WorkspaceBuilder};
+pub use crate::snapshot::{SourceChanges, SourceSnapshot};
+pub use crate::toolchain::{CargoVersion, RustcVersion, Toolchain, ToolchainError};
+pub use crate::workspace::{Workspace, WorkspaceBuilder, WorkspaceError, WorkspaceLock};
 
 pub(crate) static HOST_TARGET: &str = include_str!(concat!(env!("OUT_DIR"), "/target"));
@@ -0,0 +1,128 @@
+use failure::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Metadata of the package being built, as reported by `cargo metadata`.
+///
+/// This is returned by [`Build::metadata`](struct.Build.html#method.metadata).
+#[derive(Debug, Clone, Deserialize)]
+pub struct PackageMetadata {
+    name: String,
+    version: String,
+    edition: String,
+    features: BTreeMap<String, Vec<String>>,
+    dependencies: Vec<PackageDependency>,
+}
+
+impl PackageMetadata {
+    /// Return the name of the package.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the version of the package.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Return the Rust edition of the package, for example `2018`.
+    pub fn edition(&self) -> &str {
+        &self.edition
+    }
+
+    /// Return the features declared by the package, mapped to the features and optional
+    /// dependencies they enable.
+    pub fn features(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.features
+    }
+
+    /// Return the dependencies declared by the package.
+    pub fn dependencies(&self) -> &[PackageDependency] {
+        &self.dependencies
+    }
+}
+
+/// Dependency declared by a package, as reported by `cargo metadata`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PackageDependency {
+    name: String,
+    req: String,
+    kind: Option<String>,
+    optional: bool,
+}
+
+impl PackageDependency {
+    /// Return the name of the dependency.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the version requirement of the dependency, for example `^1.0`.
+    pub fn req(&self) -> &str {
+        &self.req
+    }
+
+    /// Return the kind of the dependency (`dev` or `build`), or `None` for normal dependencies.
+    pub fn kind(&self) -> Option<&str> {
+        self.kind.as_deref()
+    }
+
+    /// Return whether the dependency is optional.
+    pub fn is_optional(&self) -> bool {
+        self.optional
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<PackageMetadata>,
+}
+
+/// Parse the output of `cargo metadata --no-deps --format-version 1` for a single package.
+pub(crate) fn parse(json: &str) -> Result<PackageMetadata, Error> {
+    let metadata: Metadata = serde_json::from_str(json)?;
+    metadata
+        .packages
+        .into_iter()
+        .next()
+        .ok_or_else(|| failure::err_msg("no packages in the output of `cargo metadata`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use failure::Error;
+
+    #[test]
+    fn test_parse() -> Result<(), Error> {
+        let json = r#"{
+            "packages": [{
+                "name": "foo",
+                "version": "1.0.0",
+                "id": "foo 1.0.0 (path+file:///tmp/foo)",
+                "edition": "2018",
+                "features": {"default": ["bar"]},
+                "dependencies": [
+                    {"name": "bar", "req": "^1.0", "kind": null, "optional": true},
+                    {"name": "baz", "req": "^0.2", "kind": "dev", "optional": false}
+                ]
+            }],
+            "workspace_members": ["foo 1.0.0 (path+file:///tmp/foo)"],
+            "version": 1
+        }"#;
+
+        let metadata = super::parse(json)?;
+        assert_eq!(metadata.name(), "foo");
+        assert_eq!(metadata.version(), "1.0.0");
+        assert_eq!(metadata.edition(), "2018");
+        assert_eq!(metadata.features()["default"], vec!["bar".to_string()]);
+
+        let deps = metadata.dependencies();
+        assert_eq!(deps.len(), 2);
+        assert_eq!(deps[0].name(), "bar");
+        assert_eq!(deps[0].req(), "^1.0");
+        assert_eq!(deps[0].kind(), None);
+        assert!(deps[0].is_optional());
+        assert_eq!(deps[1].kind(), Some("dev"));
+        Ok(())
+    }
+}
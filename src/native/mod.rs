@@ -7,3 +7,13 @@ pub(crate) use self::unix::*;
 mod windows;
 #[cfg(windows)]
 pub(crate) use self::windows::*;
+
+/// Resources used by a terminated child process (and the descendants it waited for), as reported
+/// by the operating system.
+#[derive(Copy, Clone)]
+pub(crate) struct ChildUsage {
+    /// Total CPU time (user and system) used by the child.
+    pub(crate) cpu_time: std::time::Duration,
+    /// Maximum resident set size of the child, in bytes.
+    pub(crate) max_rss: u64,
+}
@@ -1,11 +1,15 @@
+use super::ChildUsage;
 use failure::Error;
 use nix::{
+    libc,
     sys::signal::{kill, Signal},
     unistd::{Gid, Pid, Uid},
 };
 use std::convert::AsRef;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
+use std::process::ExitStatus;
 
 const EXECUTABLE_BITS: u32 = 0o5;
 
@@ -14,10 +18,59 @@ pub(crate) fn kill_process(id: u32) -> Result<(), Error> {
     Ok(())
 }
 
+pub(crate) fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+/// Return the resources used by the child process with the provided PID if it exited, without
+/// reaping it: the child is still waited for by whoever spawned it.
+#[cfg(target_os = "linux")]
+pub(crate) fn exited_child_usage(pid: u32) -> Option<ChildUsage> {
+    // Unlike the libc wrapper, the waitid syscall can also return the resources used by the
+    // child, and WNOWAIT leaves the child in a waitable state.
+    let mut info = std::mem::MaybeUninit::<libc::siginfo_t>::zeroed();
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_waitid,
+            libc::P_PID,
+            pid as libc::id_t,
+            info.as_mut_ptr(),
+            libc::WEXITED | libc::WNOWAIT | libc::WNOHANG,
+            usage.as_mut_ptr(),
+        )
+    };
+    // si_signo is set to zero when the child didn't exit yet.
+    if res != 0 || unsafe { info.assume_init() }.si_signo != libc::SIGCHLD {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+
+    let duration = |time: libc::timeval| {
+        std::time::Duration::from_secs(time.tv_sec as u64)
+            + std::time::Duration::from_micros(time.tv_usec as u64)
+    };
+    Some(ChildUsage {
+        cpu_time: duration(usage.ru_utime) + duration(usage.ru_stime),
+        // ru_maxrss is reported in kilobytes.
+        max_rss: usage.ru_maxrss as u64 * 1024,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn exited_child_usage(_pid: u32) -> Option<ChildUsage> {
+    // Other platforms can't get the resources used by a child without reaping it.
+    None
+}
+
 pub(crate) fn current_user() -> Option<u32> {
     Some(Uid::effective().into())
 }
 
+pub(crate) fn current_user_and_group() -> Option<(u32, u32)> {
+    Some((Uid::effective().into(), current_group()))
+}
+
 fn current_group() -> u32 {
     Gid::effective().into()
 }
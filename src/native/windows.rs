@@ -1,6 +1,8 @@
+use super::ChildUsage;
 use failure::{bail, Error};
 use std::fs::File;
 use std::path::Path;
+use std::process::ExitStatus;
 use winapi::um::handleapi::CloseHandle;
 use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
 use winapi::um::winnt::PROCESS_TERMINATE;
@@ -22,7 +24,17 @@ pub(crate) fn kill_process(id: u32) -> Result<(), Error> {
     Ok(())
 }
 
-pub(crate) fn current_user() -> Option<u32> {
+pub(crate) fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    // Signals don't exist on Windows.
+    None
+}
+
+pub(crate) fn exited_child_usage(_pid: u32) -> Option<ChildUsage> {
+    // The resources used by child processes are not measured on Windows.
+    None
+}
+
+pub(crate) fn current_user_and_group() -> Option<(u32, u32)> {
     None
 }
 
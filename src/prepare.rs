@@ -1,20 +1,36 @@
+use crate::build::{CrateDependency, CratePatch, Patch};
 use crate::cmd::Command;
-use crate::{build::CratePatch, Crate, Toolchain, Workspace};
+use crate::{Crate, DependencyKind, Toolchain, Workspace};
 use failure::{Error, Fail, ResultExt};
 use log::info;
 use std::path::Path;
+use std::sync::Arc;
 use toml::{
     value::{Array, Table},
     Value,
 };
 
+/// Options changing how a crate is prepared, configured through
+/// [`BuildBuilder`](../struct.BuildBuilder.html).
+#[derive(Clone, Default)]
+pub(crate) struct PrepareOptions {
+    pub(crate) patches: Vec<CratePatch>,
+    pub(crate) dependencies: Vec<CrateDependency>,
+    pub(crate) update_lockfile: bool,
+    pub(crate) require_lockfile: bool,
+    pub(crate) extract_progress: Option<ExtractProgress>,
+    pub(crate) keep_existing_source: bool,
+}
+
+pub(crate) type ExtractProgress = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
 pub(crate) struct Prepare<'a> {
     workspace: &'a Workspace,
     toolchain: &'a Toolchain,
     krate: &'a Crate,
     source_dir: &'a Path,
     lockfile_captured: bool,
-    patches: Vec<CratePatch>,
+    options: PrepareOptions,
 }
 
 impl<'a> Prepare<'a> {
@@ -23,7 +39,7 @@ impl<'a> Prepare<'a> {
         toolchain: &'a Toolchain,
         krate: &'a Crate,
         source_dir: &'a Path,
-        patches: Vec<CratePatch>,
+        options: PrepareOptions,
     ) -> Self {
         Self {
             workspace,
@@ -31,16 +47,26 @@ impl<'a> Prepare<'a> {
             krate,
             source_dir,
             lockfile_captured: false,
-            patches,
+            options,
         }
     }
 
     pub(crate) fn prepare(&mut self) -> Result<(), Error> {
-        self.krate.copy_source_to(self.workspace, self.source_dir)?;
+        self.krate.copy_source_to(
+            self.workspace,
+            self.source_dir,
+            self.options.extract_progress.as_deref(),
+            self.options.keep_existing_source,
+        )?;
         self.validate_manifest()?;
+        if self.options.require_lockfile && !self.options.update_lockfile {
+            // Patches and added dependencies make the lockfile outdated, so it's checked against
+            // the crate's own manifest before tweaking it.
+            self.check_lockfile()?;
+        }
         self.tweak_toml()?;
-        self.capture_lockfile(false)?;
-        self.fetch_deps()?;
+        self.capture_lockfile(self.options.update_lockfile)?;
+        self.fetch_deps(true)?;
 
         Ok(())
     }
@@ -70,12 +96,46 @@ impl<'a> Prepare<'a> {
 
     fn tweak_toml(&self) -> Result<(), Error> {
         let path = self.source_dir.join("Cargo.toml");
-        let mut tweaker = TomlTweaker::new(&self.krate, &path, &self.patches)?;
+        let mut tweaker = TomlTweaker::new(&self.krate, &path, &self.options)?;
         tweaker.tweak();
         tweaker.save(&path)?;
         Ok(())
     }
 
+    fn check_lockfile(&self) -> Result<(), Error> {
+        if !self.source_dir.join("Cargo.lock").exists() {
+            return Err(PrepareError::OutdatedLockfile.into());
+        }
+
+        let mut outdated_lockfile = false;
+        let mut cause = None;
+        let res = Command::new(self.workspace, self.toolchain.cargo())
+            .args(&[
+                "metadata",
+                "--locked",
+                "--format-version",
+                "1",
+                "--manifest-path",
+                "Cargo.toml",
+            ])
+            .cd(self.source_dir)
+            .log_output(false)
+            .process_lines(&mut |line, _| {
+                if is_outdated_lockfile(line) {
+                    outdated_lockfile = true;
+                } else if cause.is_none() {
+                    cause = cargo_failure_cause(line);
+                }
+            })
+            .run();
+        match (res, cause) {
+            (Ok(_), _) => Ok(()),
+            (Err(_), _) if outdated_lockfile => Err(PrepareError::OutdatedLockfile.into()),
+            (Err(_), Some(cause)) => Err(cause.into()),
+            (err, None) => err,
+        }
+    }
+
     fn capture_lockfile(&mut self, force: bool) -> Result<(), Error> {
         if !force && self.source_dir.join("Cargo.lock").exists() {
             info!(
@@ -85,7 +145,7 @@ impl<'a> Prepare<'a> {
             return Ok(());
         }
 
-        let mut yanked_deps = false;
+        let mut cause = None;
         let mut cmd = Command::new(self.workspace, self.toolchain.cargo()).args(&[
             "generate-lockfile",
             "--manifest-path",
@@ -98,62 +158,103 @@ impl<'a> Prepare<'a> {
         }
         let res = cmd
             .cd(self.source_dir)
-            .process_lines(&mut |line| {
-                if line.contains("failed to select a version for the requirement") {
-                    yanked_deps = true;
+            .process_lines(&mut |line, _| {
+                if cause.is_none() {
+                    cause = cargo_failure_cause(line);
                 }
             })
             .run();
-        match res {
-            Err(_) if yanked_deps => {
-                return Err(PrepareError::YankedDependencies.into());
-            }
-            other => other?,
+        match (res, cause) {
+            (Err(_), Some(cause)) => return Err(cause.into()),
+            (other, _) => other?,
         }
         self.lockfile_captured = true;
         Ok(())
     }
 
-    fn fetch_deps(&mut self) -> Result<(), Error> {
+    fn fetch_deps(&mut self, locked: bool) -> Result<(), Error> {
         let mut outdated_lockfile = false;
-        let res = Command::new(self.workspace, self.toolchain.cargo())
-            .args(&["fetch", "--locked", "--manifest-path", "Cargo.toml"])
+        let mut cause = None;
+        let mut cmd = Command::new(self.workspace, self.toolchain.cargo()).args(&["fetch"]);
+        if locked {
+            cmd = cmd.args(&["--locked"]);
+        }
+        let res = cmd
+            .args(&["--manifest-path", "Cargo.toml"])
             .cd(&self.source_dir)
-            .process_lines(&mut |line| {
-                if line.ends_with(
-                    "Cargo.lock needs to be updated but --locked was passed to prevent this",
-                ) {
+            .process_lines(&mut |line, _| {
+                if is_outdated_lockfile(line) {
                     outdated_lockfile = true;
+                } else if cause.is_none() {
+                    cause = cargo_failure_cause(line);
                 }
             })
             .run();
-        match res {
-            Ok(_) => {}
-            Err(_) if outdated_lockfile && !self.lockfile_captured => {
+        match (res, cause) {
+            (Ok(_), _) => {}
+            (Err(_), _) if outdated_lockfile && self.options.require_lockfile => {
+                // The lockfile was up to date before tweaking the manifest, so only the entries
+                // affected by the tweaks are updated, keeping the rest of the locked versions.
+                info!("the lockfile is outdated after tweaking the manifest, updating it");
+                return self.fetch_deps(false);
+            }
+            (Err(_), _) if outdated_lockfile && !self.lockfile_captured => {
                 info!("the lockfile is outdated, regenerating it");
                 // Force-update the lockfile and recursively call this function to fetch
                 // dependencies again.
                 self.capture_lockfile(true)?;
-                return self.fetch_deps();
+                return self.fetch_deps(true);
             }
-            err => return err,
+            (Err(_), Some(cause)) => return Err(cause.into()),
+            (err, None) => return err,
         }
         Ok(())
     }
 }
 
+/// Detect whether cargo failed because the lockfile is outdated and `--locked` was passed.
+fn is_outdated_lockfile(line: &str) -> bool {
+    line.ends_with("Cargo.lock needs to be updated but --locked was passed to prevent this")
+        // Message printed by newer versions of cargo.
+        || (line.contains("cannot update the lock file")
+            && line.ends_with("because --locked was passed to prevent this"))
+}
+
+/// Messages printed by cargo when it fails to reach the network.
+static CARGO_NETWORK_ERRORS: &[&str] = &[
+    "spurious network error",
+    "failed to download",
+    "Couldn't resolve host name",
+    "Could not resolve host",
+    "failed to connect to",
+    "Timeout was reached",
+];
+
+/// Detect why cargo failed to resolve or fetch the dependencies from a line of its output.
+fn cargo_failure_cause(line: &str) -> Option<PrepareError> {
+    if line.contains("failed to select a version for the requirement") {
+        Some(PrepareError::YankedDependencies)
+    } else if line.contains("no matching package named") {
+        Some(PrepareError::MissingDependencies)
+    } else if CARGO_NETWORK_ERRORS.iter().any(|err| line.contains(err)) {
+        Some(PrepareError::NetworkError)
+    } else {
+        None
+    }
+}
+
 struct TomlTweaker<'a> {
     krate: &'a Crate,
     table: Table,
     dir: Option<&'a Path>,
-    patches: Vec<CratePatch>,
+    options: &'a PrepareOptions,
 }
 
 impl<'a> TomlTweaker<'a> {
     pub fn new(
         krate: &'a Crate,
         cargo_toml: &'a Path,
-        patches: &[CratePatch],
+        options: &'a PrepareOptions,
     ) -> Result<Self, Error> {
         let toml_content = ::std::fs::read_to_string(cargo_toml)
             .with_context(|_| PrepareError::MissingCargoToml)?;
@@ -166,17 +267,17 @@ impl<'a> TomlTweaker<'a> {
             krate,
             table,
             dir,
-            patches: patches.to_vec(),
+            options,
         })
     }
 
     #[cfg(test)]
-    fn new_with_table(krate: &'a Crate, table: Table, patches: &[CratePatch]) -> Self {
+    fn new_with_table(krate: &'a Crate, table: Table, options: &'a PrepareOptions) -> Self {
         TomlTweaker {
             krate,
             table,
             dir: None,
-            patches: patches.to_vec(),
+            options,
         }
     }
 
@@ -188,6 +289,7 @@ impl<'a> TomlTweaker<'a> {
         self.remove_workspaces();
         self.remove_unwanted_cargo_features();
         self.remove_dependencies();
+        self.add_dependencies();
         self.apply_patches();
 
         info!("finished tweaking {}", self.krate);
@@ -290,8 +392,36 @@ impl<'a> TomlTweaker<'a> {
         }
     }
 
+    fn add_dependencies(&mut self) {
+        let krate = self.krate.to_string();
+
+        for dep in &self.options.dependencies {
+            let section = match dep.kind {
+                DependencyKind::Normal => "dependencies",
+                DependencyKind::Dev => "dev-dependencies",
+                DependencyKind::Build => "build-dependencies",
+                DependencyKind::__NonExaustive => {
+                    panic!("do not create __NonExaustive variants manually")
+                }
+            };
+
+            let deps = self
+                .table
+                .entry(section.to_string())
+                .or_insert_with(|| Value::Table(Table::new()));
+            if let Value::Table(deps) = deps {
+                let previous = deps.insert(dep.name.clone(), Value::String(dep.req.clone()));
+                if previous.is_some() {
+                    info!("replaced {} {} of {}", section, dep.name, krate);
+                } else {
+                    info!("added {} {} to {}", section, dep.name, krate);
+                }
+            }
+        }
+    }
+
     fn apply_patches(&mut self) {
-        if !self.patches.is_empty() {
+        if !self.options.patches.is_empty() {
             let mut patch_table = self.table.get_mut("patch");
             let patch_table = match patch_table {
                 Some(ref mut pt) => pt,
@@ -302,23 +432,34 @@ impl<'a> TomlTweaker<'a> {
                 }
             };
 
-            let mut cratesio_table = patch_table.get_mut("crates-io");
-            let cratesio_table = match cratesio_table {
-                Some(ref mut cio) => cio,
-                None => {
-                    patch_table
-                        .as_table_mut()
-                        .unwrap()
-                        .insert("crates-io".to_string(), Value::Table(Table::new()));
-                    patch_table.get_mut("crates-io").unwrap()
+            for patch in self.options.patches.iter().cloned() {
+                let mut table = Table::new();
+                match patch.patch {
+                    Patch::Git { url, branch } => {
+                        table.insert("git".into(), Value::String(url));
+                        table.insert("branch".into(), Value::String(branch));
+                    }
+                    Patch::Path(path) => {
+                        table.insert(
+                            "path".into(),
+                            Value::String(path.to_string_lossy().into_owned()),
+                        );
+                    }
+                    Patch::CratesIo { version } => {
+                        table.insert("version".into(), Value::String(version));
+                    }
+                    Patch::__NonExaustive => {
+                        panic!("do not create __NonExaustive variants manually")
+                    }
                 }
-            };
 
-            for patch in self.patches.iter().cloned() {
-                let mut table = Table::new();
-                table.insert("git".into(), Value::String(patch.uri));
-                table.insert("branch".into(), Value::String(patch.branch));
-                cratesio_table
+                let source = patch.registry.as_deref().unwrap_or("crates-io");
+                let source_table = patch_table
+                    .as_table_mut()
+                    .unwrap()
+                    .entry(source.to_string())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                source_table
                     .as_table_mut()
                     .unwrap()
                     .insert(patch.name, Value::Table(table));
@@ -361,8 +502,14 @@ impl<'a> TomlTweaker<'a> {
 #[derive(Debug, Fail)]
 pub enum PrepareError {
     /// The git repository isn't publicly available.
+    ///
+    /// Some hosts (like GitHub) ask for credentials when a repository doesn't exist, so deleted
+    /// repositories might be reported with this error too.
     #[fail(display = "can't fetch private git repositories")]
     PrivateGitRepository,
+    /// The git repository doesn't exist.
+    #[fail(display = "the git repository doesn't exist")]
+    GitRepositoryNotFound,
     /// The crate doesn't have a `Cargo.toml` in its source code.
     #[fail(display = "missing Cargo.toml")]
     MissingCargoToml,
@@ -373,6 +520,27 @@ pub enum PrepareError {
     /// Some of this crate's dependencies were yanked, preventing Crater from fetching them.
     #[fail(display = "the crate depends on yanked dependencies")]
     YankedDependencies,
+    /// Some of this crate's dependencies don't exist in their registry.
+    #[fail(display = "the crate depends on missing dependencies")]
+    MissingDependencies,
+    /// Fetching the crate's dependencies failed due to a network error, like a DNS failure or a
+    /// timeout. Retrying later might succeed.
+    #[fail(display = "network error while fetching the crate's dependencies")]
+    NetworkError,
+    /// The crate's version was yanked, and the workspace is configured to refuse fetching yanked
+    /// crates.
+    #[fail(display = "the crate was yanked")]
+    YankedCrate,
+    /// The downloaded crate doesn't match the checksum recorded in the registry index.
+    #[fail(display = "the downloaded crate doesn't match the checksum in the index")]
+    ChecksumMismatch,
+    /// The crate, or the requested version of it, doesn't exist in the registry.
+    #[fail(display = "the crate doesn't exist in the registry")]
+    CrateNotFound,
+    /// The crate's `Cargo.lock` is missing or outdated, and the build was configured to require
+    /// an up to date lockfile.
+    #[fail(display = "the crate's lockfile is missing or outdated")]
+    OutdatedLockfile,
     #[doc(hidden)]
     #[fail(display = "this error shouldn't have happened")]
     __NonExaustive,
@@ -380,11 +548,50 @@ pub enum PrepareError {
 
 #[cfg(test)]
 mod tests {
-    use super::TomlTweaker;
-    use crate::build::CratePatch;
+    use super::{PrepareOptions, TomlTweaker};
+    use crate::build::{CrateDependency, CratePatch};
     use crate::crates::Crate;
+    use crate::{DependencyKind, Patch};
     use toml::{self, Value};
 
+    #[test]
+    fn test_cargo_failure_cause() {
+        use super::{cargo_failure_cause, PrepareError};
+
+        assert!(matches!(
+            cargo_failure_cause(
+                "error: failed to select a version for the requirement `foo = \"=1.0.0\"`"
+            ),
+            Some(PrepareError::YankedDependencies)
+        ));
+        assert!(matches!(
+            cargo_failure_cause("error: no matching package named `foo` found"),
+            Some(PrepareError::MissingDependencies)
+        ));
+        assert!(matches!(
+            cargo_failure_cause("  [6] Couldn't resolve host name (Could not resolve host: foo)"),
+            Some(PrepareError::NetworkError)
+        ));
+        assert!(cargo_failure_cause("    Updating crates.io index").is_none());
+    }
+
+    #[test]
+    fn test_is_outdated_lockfile() {
+        use super::is_outdated_lockfile;
+
+        assert!(is_outdated_lockfile(
+            "error: the lock file /opt/foo/Cargo.lock needs to be updated but --locked was passed \
+             to prevent this"
+        ));
+        assert!(is_outdated_lockfile(
+            "error: cannot update the lock file /opt/foo/Cargo.lock because --locked was passed \
+             to prevent this"
+        ));
+        assert!(!is_outdated_lockfile(
+            "error: no matching package named `foo` found"
+        ));
+    }
+
     #[test]
     fn test_tweak_table_noop() {
         let toml = toml! {
@@ -407,9 +614,9 @@ mod tests {
         let result = toml.clone();
 
         let krate = Crate::local("/dev/null".as_ref());
-        let patches: Vec<CratePatch> = Vec::new();
+        let options = PrepareOptions::default();
         let mut tweaker =
-            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &patches);
+            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &options);
         tweaker.tweak();
 
         assert_eq!(Value::Table(tweaker.table), result);
@@ -458,9 +665,9 @@ mod tests {
         };
 
         let krate = Crate::local("/dev/null".as_ref());
-        let patches: Vec<CratePatch> = Vec::new();
+        let options = PrepareOptions::default();
         let mut tweaker =
-            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &patches);
+            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &options);
         tweaker.tweak();
 
         assert_eq!(Value::Table(tweaker.table), result);
@@ -503,16 +710,139 @@ mod tests {
 
             [patch.crates-io]
             quux = { git = "https://git.example.com/quux", branch = "dev" }
+            baz = { path = "/opt/patches/baz" }
+        };
+
+        let krate = Crate::local("/dev/null".as_ref());
+        let options = PrepareOptions {
+            patches: vec![
+                CratePatch {
+                    name: "quux".into(),
+                    registry: None,
+                    patch: Patch::Git {
+                        url: "https://git.example.com/quux".into(),
+                        branch: "dev".into(),
+                    },
+                },
+                CratePatch {
+                    name: "baz".into(),
+                    registry: None,
+                    patch: Patch::Path("/opt/patches/baz".into()),
+                },
+            ],
+            ..PrepareOptions::default()
+        };
+        let mut tweaker =
+            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &options);
+        tweaker.tweak();
+
+        assert_eq!(Value::Table(tweaker.table), result);
+    }
+
+    #[test]
+    fn test_tweak_table_patches_registries() {
+        let toml = toml! {
+            [package]
+            name = "foo"
+            version = "1.0"
+
+            [dependencies]
+            bar = { version = "1.0", registry = "example" }
+
+            [patch.crates-io]
+            baz = { path = "../baz" }
+        };
+
+        let result = toml! {
+            [package]
+            name = "foo"
+            version = "1.0"
+
+            [dependencies]
+            bar = { version = "1.0", registry = "example" }
+
+            [patch.crates-io]
+            baz = { path = "../baz" }
+            quux = { git = "https://git.example.com/quux", branch = "dev" }
+
+            [patch.example]
+            bar = { version = "1.0.1" }
         };
 
         let krate = Crate::local("/dev/null".as_ref());
-        let patches = vec![CratePatch {
-            name: "quux".into(),
-            uri: "https://git.example.com/quux".into(),
-            branch: "dev".into(),
-        }];
+        let options = PrepareOptions {
+            patches: vec![
+                CratePatch {
+                    name: "quux".into(),
+                    registry: None,
+                    patch: Patch::Git {
+                        url: "https://git.example.com/quux".into(),
+                        branch: "dev".into(),
+                    },
+                },
+                CratePatch {
+                    name: "bar".into(),
+                    registry: Some("example".into()),
+                    patch: Patch::CratesIo {
+                        version: "1.0.1".into(),
+                    },
+                },
+            ],
+            ..PrepareOptions::default()
+        };
+        let mut tweaker =
+            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &options);
+        tweaker.tweak();
+
+        assert_eq!(Value::Table(tweaker.table), result);
+    }
+
+    #[test]
+    fn test_tweak_table_add_dependencies() {
+        let toml = toml! {
+            [package]
+            name = "foo"
+            version = "1.0"
+
+            [dependencies]
+            bar = "1.0"
+
+            [dev-dependencies]
+            baz = { version = "1.0", features = ["foo"] }
+        };
+
+        let result = toml! {
+            [package]
+            name = "foo"
+            version = "1.0"
+
+            [dependencies]
+            bar = "1.0"
+
+            [dev-dependencies]
+            baz = "2.0"
+            quux = "0.1"
+
+            [build-dependencies]
+            cc = "1.0"
+        };
+
+        let krate = Crate::local("/dev/null".as_ref());
+        let dependency = |name: &str, req: &str, kind| CrateDependency {
+            name: name.into(),
+            req: req.into(),
+            kind,
+        };
+        let options = PrepareOptions {
+            dependencies: vec![
+                dependency("baz", "2.0", DependencyKind::Dev),
+                dependency("quux", "0.1", DependencyKind::Dev),
+                dependency("cc", "1.0", DependencyKind::Build),
+            ],
+            ..PrepareOptions::default()
+        };
         let mut tweaker =
-            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &patches);
+            TomlTweaker::new_with_table(&krate, toml.as_table().unwrap().clone(), &options);
         tweaker.tweak();
 
         assert_eq!(Value::Table(tweaker.table), result);
@@ -0,0 +1,134 @@
+use failure::Error;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[derive(PartialEq, Eq)]
+enum FileState {
+    File(Vec<u8>),
+    Symlink(PathBuf),
+}
+
+/// Snapshot of the files in a build's source directory, used to find out which files were changed
+/// by the commands executed during the build.
+///
+/// This is returned by [`Build::snapshot_source`](struct.Build.html#method.snapshot_source).
+pub struct SourceSnapshot {
+    root: PathBuf,
+    files: BTreeMap<PathBuf, FileState>,
+}
+
+impl SourceSnapshot {
+    pub(crate) fn new(root: &Path) -> Result<Self, Error> {
+        Ok(SourceSnapshot {
+            root: root.into(),
+            files: scan(root)?,
+        })
+    }
+
+    /// Compare the snapshot with the current contents of the source directory, returning the files
+    /// that were added, modified or removed since the snapshot was taken.
+    pub fn changes(&self) -> Result<SourceChanges, Error> {
+        let current = scan(&self.root)?;
+
+        let mut changes = SourceChanges::default();
+        for (path, state) in &current {
+            match self.files.get(path) {
+                None => changes.added.push(path.clone()),
+                Some(old) if old != state => changes.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        for path in self.files.keys() {
+            if !current.contains_key(path) {
+                changes.removed.push(path.clone());
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Files changed in a build's source directory, as returned by
+/// [`SourceSnapshot::changes`](struct.SourceSnapshot.html#method.changes).
+///
+/// All the paths are relative to the root of the source directory, and are sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceChanges {
+    added: Vec<PathBuf>,
+    modified: Vec<PathBuf>,
+    removed: Vec<PathBuf>,
+}
+
+impl SourceChanges {
+    /// Return the files created after the snapshot was taken.
+    pub fn added(&self) -> &[PathBuf] {
+        &self.added
+    }
+
+    /// Return the files whose contents (or, for symlinks, whose target) changed after the
+    /// snapshot was taken.
+    pub fn modified(&self) -> &[PathBuf] {
+        &self.modified
+    }
+
+    /// Return the files removed after the snapshot was taken.
+    pub fn removed(&self) -> &[PathBuf] {
+        &self.removed
+    }
+
+    /// Return whether no file was changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+fn scan(root: &Path) -> Result<BTreeMap<PathBuf, FileState>, Error> {
+    let mut files = BTreeMap::new();
+    for entry in WalkDir::new(root).min_depth(1) {
+        let entry = entry?;
+        let state = if entry.file_type().is_symlink() {
+            FileState::Symlink(std::fs::read_link(entry.path())?)
+        } else if entry.file_type().is_file() {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut File::open(entry.path())?, &mut hasher)?;
+            FileState::File(hasher.result().to_vec())
+        } else {
+            continue;
+        };
+        files.insert(entry.path().strip_prefix(root)?.to_path_buf(), state);
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceSnapshot;
+    use failure::Error;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_changes() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("src"))?;
+        std::fs::write(dir.path().join("Cargo.toml"), b"[package]")?;
+        std::fs::write(dir.path().join("src").join("lib.rs"), b"")?;
+        std::fs::write(dir.path().join("build.rs"), b"fn main() {}")?;
+
+        let snapshot = SourceSnapshot::new(dir.path())?;
+        assert!(snapshot.changes()?.is_empty());
+
+        std::fs::write(dir.path().join("src").join("lib.rs"), b"pub fn f() {}")?;
+        std::fs::write(dir.path().join("src").join("generated.rs"), b"")?;
+        std::fs::remove_file(dir.path().join("build.rs"))?;
+
+        let changes = snapshot.changes()?;
+        assert!(!changes.is_empty());
+        assert_eq!(changes.added(), &[PathBuf::from("src/generated.rs")]);
+        assert_eq!(changes.modified(), &[PathBuf::from("src/lib.rs")]);
+        assert_eq!(changes.removed(), &[PathBuf::from("build.rs")]);
+
+        Ok(())
+    }
+}
@@ -1,13 +1,16 @@
-use crate::cmd::{Binary, Command, Runnable};
+use crate::cmd::{Binary, Command, ProcessLinesActions, Runnable};
 use crate::tools::{RUSTUP, RUSTUP_TOOLCHAIN_INSTALL_MASTER};
 use crate::Workspace;
-use failure::{bail, Error, ResultExt};
+use failure::{bail, Error, Fail, ResultExt};
 use log::info;
 use std::borrow::Cow;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 pub(crate) const MAIN_TOOLCHAIN_NAME: &str = "stable";
 
+type ProgressFn<'a> = &'a mut dyn FnMut(&str, &mut ProcessLinesActions);
+
 /// Representation of a Rust compiler toolchain.
 ///
 /// The `Toolchain` enum represents a compiler toolchain, either downloaded from rustup or from the
@@ -37,7 +40,36 @@ pub enum Toolchain {
         /// compiler assertions enabled.
         alt: bool,
     },
+    /// Toolchain present in a local directory, for example a compiler built from source. The
+    /// directory must contain the `bin` and `lib` directories of the toolchain, and installing
+    /// it will link it in the workspace's rustup installation with the provided name.
+    ///
+    /// Local toolchains are listed as [`Dist`](#variant.Dist) toolchains by
+    /// [`Workspace::installed_toolchains`](struct.Workspace.html#method.installed_toolchains).
+    Local {
+        /// The name the toolchain is linked with, which must not conflict with the name of a
+        /// toolchain distributed by rustup (like `stable` or `nightly-2020-01-01`).
+        name: Cow<'static, str>,
+        /// Path of the toolchain's directory.
+        path: PathBuf,
+    },
+    #[doc(hidden)]
+    __NonExaustive,
+}
+
+/// Error happened while managing a toolchain.
+#[derive(Debug, Fail)]
+pub enum ToolchainError {
+    /// The requested component is not available for the toolchain, for example because it failed
+    /// to build on that nightly. The component's name is the first value.
+    #[fail(display = "component {} is not available for this toolchain", _0)]
+    ComponentNotAvailable(String),
+    /// The requested toolchain doesn't exist, for example because a nightly wasn't released on
+    /// that date. The toolchain's name is the first value.
+    #[fail(display = "toolchain {} doesn't exist", _0)]
+    NotFound(String),
     #[doc(hidden)]
+    #[fail(display = "this error shouldn't have happened")]
     __NonExaustive,
 }
 
@@ -46,18 +78,79 @@ impl Toolchain {
         name: Cow::Borrowed(MAIN_TOOLCHAIN_NAME),
     };
 
+    /// Create a toolchain distributed by rustup, with the same name you'd use with `rustup
+    /// toolchain install <name>`.
+    pub fn dist(name: &str) -> Self {
+        Toolchain::Dist {
+            name: Cow::Owned(name.into()),
+        }
+    }
+
+    /// Create a toolchain from the CI artifacts of a merge commit in the [rust-lang/rust] repo.
+    /// See the [`CI`](#variant.CI) variant for more details.
+    ///
+    /// [rust-lang/rust]: https://github.com/rust-lang/rust
+    pub fn ci(sha: &str, alt: bool) -> Self {
+        Toolchain::CI {
+            sha: Cow::Owned(sha.into()),
+            alt,
+        }
+    }
+
     /// Download and install the toolchain.
+    ///
+    /// CI toolchains are downloaded only once, as their artifacts never change: if the toolchain
+    /// is already installed in the workspace this method does nothing.
     pub fn install(&self, workspace: &Workspace) -> Result<(), Error> {
+        self.install_inner(workspace, None)
+    }
+
+    /// Download and install the toolchain, calling `progress` with each line of output of the
+    /// installer (like rustup's `info: downloading component 'rustc'`), so that the progress of
+    /// long installs can be shown to the user.
+    ///
+    /// The lines can be hidden or replaced in the logs through the provided
+    /// [`ProcessLinesActions`](cmd/struct.ProcessLinesActions.html), like with
+    /// [`Command::process_lines`](cmd/struct.Command.html#method.process_lines).
+    pub fn install_with_progress(
+        &self,
+        workspace: &Workspace,
+        progress: &mut dyn FnMut(&str, &mut ProcessLinesActions),
+    ) -> Result<(), Error> {
+        self.install_inner(workspace, Some(progress))
+    }
+
+    fn install_inner(
+        &self,
+        workspace: &Workspace,
+        progress: Option<ProgressFn>,
+    ) -> Result<(), Error> {
         match self {
-            Self::Dist { name } => init_toolchain_from_dist(workspace, name)?,
-            Self::CI { sha, alt } => init_toolchain_from_ci(workspace, *alt, sha)?,
+            Self::Dist { name } => init_toolchain_from_dist(workspace, name, progress)?,
+            Self::CI { sha, alt } => init_toolchain_from_ci(workspace, *alt, sha, progress)?,
+            Self::Local { name, path } => {
+                init_toolchain_from_path(workspace, name, path, progress)?
+            }
             Self::__NonExaustive => panic!("do not create __NonExaustive variants manually"),
         }
 
         Ok(())
     }
 
+    /// Check whether the toolchain is installed in the workspace, without invoking rustup.
+    ///
+    /// Toolchains from rustup's dist server are considered installed even when they were
+    /// installed with their full name, for example `stable-x86_64-unknown-linux-gnu` for
+    /// `stable`.
+    pub fn is_installed(&self, workspace: &Workspace) -> Result<bool, Error> {
+        is_installed_in(&workspace.rustup_home(), &self.rustup_name())
+    }
+
     /// Download and install a component for the toolchain.
+    ///
+    /// If the component is not available for the toolchain
+    /// [`ToolchainError::ComponentNotAvailable`](enum.ToolchainError.html#variant.ComponentNotAvailable)
+    /// is returned.
     pub fn add_component(&self, workspace: &Workspace, name: &str) -> Result<(), Error> {
         self.add_rustup_thing(workspace, "component", name)
     }
@@ -67,14 +160,49 @@ impl Toolchain {
         self.add_rustup_thing(workspace, "target", name)
     }
 
+    /// Return the list of targets installed for the toolchain, including the host target.
+    ///
+    /// Builds can then cross-compile to one of those targets by passing the `--target` flag to
+    /// cargo.
+    pub fn installed_targets(&self, workspace: &Workspace) -> Result<Vec<String>, Error> {
+        let toolchain_name = self.rustup_name();
+        let out = Command::new(workspace, &RUSTUP)
+            .args(&[
+                "target",
+                "list",
+                "--installed",
+                "--toolchain",
+                &toolchain_name,
+            ])
+            .log_output(false)
+            .run_capture()
+            .with_context(|_| {
+                format!(
+                    "unable to list the installed targets for toolchain {} via rustup",
+                    toolchain_name
+                )
+            })?;
+        Ok(out
+            .stdout_lines()
+            .iter()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
     fn add_rustup_thing(
         &self,
         workspace: &Workspace,
         thing: &str,
         name: &str,
     ) -> Result<(), Error> {
-        if let Self::CI { .. } = self {
-            bail!("installing {} on CI toolchains is not supported yet", thing);
+        match self {
+            Self::CI { .. } => bail!("installing {} on CI toolchains is not supported yet", thing),
+            Self::Local { .. } => {
+                bail!("installing {} on local toolchains is not supported", thing)
+            }
+            _ => {}
         }
         let toolchain_name = self.rustup_name();
         info!(
@@ -82,21 +210,40 @@ impl Toolchain {
             thing, name, toolchain_name
         );
 
-        Command::new(workspace, &RUSTUP)
+        let mut not_available = false;
+        let res = Command::new(workspace, &RUSTUP)
             .args(&[thing, "add", "--toolchain", &toolchain_name, name])
+            .process_lines(&mut |line, _| {
+                if line.contains("is unavailable for download")
+                    || line.contains("does not contain component")
+                {
+                    not_available = true;
+                }
+            })
             .run()
             .with_context(|_| {
                 format!(
                     "unable to install {} {} for toolchain {} via rustup",
                     thing, name, toolchain_name,
                 )
-            })?;
-        Ok(())
+            });
+
+        match res {
+            Err(_) if not_available && thing == "component" => {
+                Err(ToolchainError::ComponentNotAvailable(name.into()).into())
+            }
+            other => other.map_err(Error::from),
+        }
     }
 
-    /// Remove the toolchain from the rustwide workspace, freeing up disk space.
+    /// Remove the toolchain from the rustwide workspace, freeing up disk space. Nothing is done
+    /// if the toolchain is not installed.
     pub fn uninstall(&self, workspace: &Workspace) -> Result<(), Error> {
         let name = self.rustup_name();
+        if !is_installed_in(&workspace.rustup_home(), &name)? {
+            info!("toolchain {} is not installed, skipping uninstall", name);
+            return Ok(());
+        }
         Command::new(workspace, &RUSTUP)
             .args(&["toolchain", "uninstall", &name])
             .run()
@@ -152,11 +299,33 @@ impl Toolchain {
         }
     }
 
+    /// Return the detailed version of the toolchain's `rustc`, as reported by `rustc -vV`.
+    pub fn rustc_version(&self, workspace: &Workspace) -> Result<RustcVersion, Error> {
+        let mut fields = verbose_version(workspace, self.rustc())?;
+        Ok(RustcVersion {
+            release: take_field(&mut fields, "release")?,
+            host: take_field(&mut fields, "host")?,
+            commit_hash: fields.remove("commit-hash"),
+            commit_date: fields.remove("commit-date"),
+        })
+    }
+
+    /// Return the detailed version of the toolchain's `cargo`, as reported by `cargo -vV`.
+    pub fn cargo_version(&self, workspace: &Workspace) -> Result<CargoVersion, Error> {
+        let mut fields = verbose_version(workspace, self.cargo())?;
+        Ok(CargoVersion {
+            release: take_field(&mut fields, "release")?,
+            commit_hash: fields.remove("commit-hash"),
+            commit_date: fields.remove("commit-date"),
+        })
+    }
+
     fn rustup_name(&self) -> String {
         match self {
             Self::Dist { name } => name.to_string(),
             Self::CI { sha, alt: false } => sha.to_string(),
             Self::CI { sha, alt: true } => format!("{}-alt", sha),
+            Self::Local { name, .. } => name.to_string(),
             Self::__NonExaustive => panic!("do not create __NonExaustive variants manually"),
         }
     }
@@ -168,22 +337,155 @@ impl std::fmt::Display for Toolchain {
     }
 }
 
-fn init_toolchain_from_dist(workspace: &Workspace, toolchain: &str) -> Result<(), Error> {
+/// Detailed version of `rustc`, returned by
+/// [`Toolchain::rustc_version`](enum.Toolchain.html#method.rustc_version).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RustcVersion {
+    release: String,
+    host: String,
+    commit_hash: Option<String>,
+    commit_date: Option<String>,
+}
+
+impl RustcVersion {
+    /// Return the release version, for example `1.40.0` or `1.42.0-nightly`.
+    pub fn release(&self) -> &str {
+        &self.release
+    }
+
+    /// Return the target triple of the host the compiler runs on.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Return the hash of the commit the compiler was built from, if known.
+    pub fn commit_hash(&self) -> Option<&str> {
+        self.commit_hash.as_deref()
+    }
+
+    /// Return the date of the commit the compiler was built from, if known.
+    pub fn commit_date(&self) -> Option<&str> {
+        self.commit_date.as_deref()
+    }
+}
+
+/// Detailed version of `cargo`, returned by
+/// [`Toolchain::cargo_version`](enum.Toolchain.html#method.cargo_version).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CargoVersion {
+    release: String,
+    commit_hash: Option<String>,
+    commit_date: Option<String>,
+}
+
+impl CargoVersion {
+    /// Return the release version, for example `1.40.0` or `1.42.0-nightly`.
+    pub fn release(&self) -> &str {
+        &self.release
+    }
+
+    /// Return the hash of the commit cargo was built from, if known.
+    pub fn commit_hash(&self) -> Option<&str> {
+        self.commit_hash.as_deref()
+    }
+
+    /// Return the date of the commit cargo was built from, if known.
+    pub fn commit_date(&self) -> Option<&str> {
+        self.commit_date.as_deref()
+    }
+}
+
+fn verbose_version<R: Runnable>(
+    workspace: &Workspace,
+    binary: R,
+) -> Result<HashMap<String, String>, Error> {
+    let out = Command::new(workspace, binary)
+        .args(&["-vV"])
+        .log_output(false)
+        .run_capture()?;
+    Ok(parse_verbose_version(out.stdout_lines()))
+}
+
+/// Parse the `key: value` lines of the output of `rustc -vV` and `cargo -vV`. Unknown values are
+/// omitted, as older versions print `unknown` instead of the commit hash and date.
+fn parse_verbose_version(lines: &[String]) -> HashMap<String, String> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let key = parts.next()?.trim();
+            let value = parts.next()?.trim();
+            if value.is_empty() || value == "unknown" {
+                None
+            } else {
+                Some((key.to_string(), value.to_string()))
+            }
+        })
+        .collect()
+}
+
+fn take_field(fields: &mut HashMap<String, String>, name: &str) -> Result<String, Error> {
+    fields
+        .remove(name)
+        .ok_or_else(|| failure::format_err!("missing `{}` in the version output", name))
+}
+
+fn init_toolchain_from_dist(
+    workspace: &Workspace,
+    toolchain: &str,
+    mut progress: Option<ProgressFn>,
+) -> Result<(), Error> {
     info!("installing toolchain {}", toolchain);
-    Command::new(workspace, &RUSTUP)
+    let mut not_found = false;
+    let res = Command::new(workspace, &RUSTUP)
         .args(&["toolchain", "install", toolchain])
+        .process_lines(&mut |line, actions| {
+            if is_toolchain_not_found(line) {
+                not_found = true;
+            }
+            if let Some(progress) = &mut progress {
+                progress(line, actions);
+            }
+        })
         .run()
-        .with_context(|_| format!("unable to install toolchain {} via rustup", toolchain))?;
+        .with_context(|_| format!("unable to install toolchain {} via rustup", toolchain));
 
-    Ok(())
+    match res {
+        Err(_) if not_found => Err(ToolchainError::NotFound(toolchain.into()).into()),
+        Err(err) => Err(err.into()),
+        Ok(()) => Ok(()),
+    }
 }
 
-fn init_toolchain_from_ci(workspace: &Workspace, alt: bool, sha: &str) -> Result<(), Error> {
-    if alt {
-        info!("installing toolchain {}-alt", sha);
+/// Detect from a line of the output of `rustup toolchain install` whether the toolchain doesn't
+/// exist, to distinguish it from other failures like network errors.
+fn is_toolchain_not_found(line: &str) -> bool {
+    line.contains("no release found for")
+        || line.contains("could not download nonexistent rust version")
+        || line.contains("is not installable")
+}
+
+fn init_toolchain_from_ci(
+    workspace: &Workspace,
+    alt: bool,
+    sha: &str,
+    mut progress: Option<ProgressFn>,
+) -> Result<(), Error> {
+    let name = if alt {
+        format!("{}-alt", sha)
     } else {
-        info!("installing toolchain {}", sha);
+        sha.to_string()
+    };
+    if workspace
+        .rustup_home()
+        .join("toolchains")
+        .join(&name)
+        .is_dir()
+    {
+        info!("toolchain {} is already installed", name);
+        return Ok(());
     }
+    info!("installing toolchain {}", name);
 
     let mut args = vec![sha, "-c", "cargo"];
     if alt {
@@ -192,6 +494,11 @@ fn init_toolchain_from_ci(workspace: &Workspace, alt: bool, sha: &str) -> Result
 
     Command::new(workspace, &RUSTUP_TOOLCHAIN_INSTALL_MASTER)
         .args(&args)
+        .process_lines(&mut |line, actions| {
+            if let Some(progress) = &mut progress {
+                progress(line, actions);
+            }
+        })
         .run()
         .with_context(|_| {
             format!(
@@ -203,6 +510,38 @@ fn init_toolchain_from_ci(workspace: &Workspace, alt: bool, sha: &str) -> Result
     Ok(())
 }
 
+fn init_toolchain_from_path(
+    workspace: &Workspace,
+    name: &str,
+    path: &Path,
+    mut progress: Option<ProgressFn>,
+) -> Result<(), Error> {
+    info!("linking toolchain {} from {}", name, path.display());
+    let rustc = path
+        .join("bin")
+        .join(format!("rustc{}", std::env::consts::EXE_SUFFIX));
+    if !rustc.is_file() {
+        bail!(
+            "{} doesn't look like a toolchain: {} is missing",
+            path.display(),
+            rustc.display()
+        );
+    }
+
+    Command::new(workspace, &RUSTUP)
+        .args(&["toolchain", "link", name])
+        .args(&[crate::utils::normalize_path(path)])
+        .process_lines(&mut |line, actions| {
+            if let Some(progress) = &mut progress {
+                progress(line, actions);
+            }
+        })
+        .run()
+        .with_context(|_| format!("unable to link toolchain {} via rustup", name))?;
+
+    Ok(())
+}
+
 struct RustupProxy<'a> {
     toolchain: &'a Toolchain,
     name: &'static str,
@@ -218,6 +557,37 @@ impl Runnable for RustupProxy<'_> {
     }
 }
 
+/// Check whether a toolchain with the provided name is installed in the rustup home. Names
+/// without a host triple match the toolchains installed for any host, as rustup adds the host
+/// triple to the names of the toolchains it installs.
+fn is_installed_in(rustup_home: &Path, name: &str) -> Result<bool, Error> {
+    let toolchains = rustup_home.join("toolchains");
+    if !toolchains.is_dir() {
+        return Ok(false);
+    }
+    for entry in std::fs::read_dir(&toolchains)? {
+        let entry = entry?.file_name();
+        let entry = match entry.to_str() {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if entry == name {
+            return Ok(true);
+        }
+        // Host triples start with the architecture, so they can't be confused with the date of
+        // a nightly toolchain (for example `nightly` and `nightly-2020-01-01-{host}`).
+        if let Some(host) = entry
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('-'))
+        {
+            if host.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
 pub(crate) fn list_installed(rustup_home: &Path) -> Result<Vec<Toolchain>, Error> {
     let update_hashes = rustup_home.join("update-hashes");
 
@@ -255,6 +625,108 @@ mod tests {
     use super::Toolchain;
     use failure::Error;
 
+    #[test]
+    fn test_is_toolchain_not_found() {
+        let cases = [
+            ("error: no release found for 'nightly-2010-01-01'", true),
+            (
+                "error: could not download nonexistent rust version \
+                 `1.999.0-x86_64-unknown-linux-gnu`: could not download file",
+                true,
+            ),
+            (
+                "error: toolchain 'foo-x86_64-unknown-linux-gnu' is not installable",
+                true,
+            ),
+            (
+                "info: syncing channel updates for 'stable-x86_64-unknown-linux-gnu'",
+                false,
+            ),
+            ("info: downloading component 'rustc'", false),
+            (
+                "error: could not download file from \
+                 'https://static.rust-lang.org/dist/channel-rust-stable.toml.sha256'",
+                false,
+            ),
+            (
+                "error: component 'rls' for target 'x86_64-unknown-linux-gnu' is unavailable \
+                 for download for channel 'nightly'",
+                false,
+            ),
+        ];
+        for (line, not_found) in &cases {
+            assert_eq!(super::is_toolchain_not_found(line), *not_found, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse_verbose_version() {
+        let output = [
+            "rustc 1.40.0 (73528e339 2019-12-16)",
+            "binary: rustc",
+            "commit-hash: 73528e339aae0f17a15ffa49a8ac608f50c6cf14",
+            "commit-date: 2019-12-16",
+            "host: x86_64-unknown-linux-gnu",
+            "release: 1.40.0",
+            "LLVM version: 9.0",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+
+        let fields = super::parse_verbose_version(&output);
+        assert_eq!(fields["release"], "1.40.0");
+        assert_eq!(fields["host"], "x86_64-unknown-linux-gnu");
+        assert_eq!(fields["commit-date"], "2019-12-16");
+        assert_eq!(fields["LLVM version"], "9.0");
+
+        let output = vec!["commit-hash: unknown".to_string()];
+        assert!(super::parse_verbose_version(&output).is_empty());
+    }
+
+    #[test]
+    fn test_is_installed_in() -> Result<(), Error> {
+        let rustup_home = tempfile::tempdir()?;
+        assert!(!super::is_installed_in(rustup_home.path(), "stable")?);
+
+        let toolchains = rustup_home.path().join("toolchains");
+        std::fs::create_dir_all(toolchains.join("stable-x86_64-unknown-linux-gnu"))?;
+        std::fs::create_dir_all(toolchains.join("nightly-2020-01-01-x86_64-unknown-linux-gnu"))?;
+        std::fs::create_dir_all(toolchains.join("0000000000000000000000000000000000000000"))?;
+
+        let installed = |name| super::is_installed_in(rustup_home.path(), name);
+        assert!(installed("stable")?);
+        assert!(installed("stable-x86_64-unknown-linux-gnu")?);
+        assert!(installed("nightly-2020-01-01")?);
+        assert!(installed("0000000000000000000000000000000000000000")?);
+        assert!(!installed("nightly")?);
+        assert!(!installed("beta")?);
+        assert!(!installed("0000000000000000000000000000000000000000-alt")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_installed_in_host_suffixed() -> Result<(), Error> {
+        // rustup only creates the host-suffixed directory when installing a dist toolchain, which
+        // is what uninstall() relies on to decide whether there's something to remove.
+        let rustup_home = tempfile::tempdir()?;
+        let toolchains = rustup_home.path().join("toolchains");
+        std::fs::create_dir_all(toolchains.join("1.40.0-aarch64-unknown-linux-gnu"))?;
+        std::fs::create_dir_all(toolchains.join("beta-x86_64-pc-windows-msvc"))?;
+
+        let installed = |name: &str| {
+            let toolchain = Toolchain::Dist {
+                name: name.to_string().into(),
+            };
+            super::is_installed_in(rustup_home.path(), &toolchain.rustup_name())
+        };
+        assert!(installed("1.40.0")?);
+        assert!(installed("beta")?);
+        assert!(!installed("1.40")?);
+        assert!(!installed("stable")?);
+        Ok(())
+    }
+
     #[test]
     fn test_list_installed() -> Result<(), Error> {
         const DIST_NAME: &str = "stable-x86_64-unknown-linux-gnu";
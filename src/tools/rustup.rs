@@ -6,9 +6,8 @@ use failure::{Error, ResultExt};
 use std::env::consts::EXE_SUFFIX;
 use std::fs::{self, File};
 use std::io;
-use tempfile::tempdir;
 
-static RUSTUP_BASE_URL: &str = "https://static.rust-lang.org/rustup/dist";
+static DEFAULT_RUSTUP_UPDATE_ROOT: &str = "https://static.rust-lang.org/rustup";
 
 pub(crate) struct Rustup;
 
@@ -16,6 +15,24 @@ impl Runnable for Rustup {
     fn name(&self) -> Binary {
         Binary::ManagedByRustwide("rustup".into())
     }
+
+    fn prepare_command<'w, 'pl>(&self, cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
+        match cmd.workspace() {
+            Some(workspace) => mirrors_env(workspace, cmd),
+            None => cmd,
+        }
+    }
+}
+
+/// Point rustup to the mirrors configured in the workspace, if any.
+fn mirrors_env<'w, 'pl>(workspace: &Workspace, mut cmd: Command<'w, 'pl>) -> Command<'w, 'pl> {
+    if let Some(url) = workspace.rustup_dist_server() {
+        cmd = cmd.env("RUSTUP_DIST_SERVER", url);
+    }
+    if let Some(url) = workspace.rustup_update_root() {
+        cmd = cmd.env("RUSTUP_UPDATE_ROOT", url);
+    }
+    cmd
 }
 
 impl Tool for Rustup {
@@ -37,8 +54,10 @@ impl Tool for Rustup {
         fs::create_dir_all(workspace.rustup_home())?;
 
         let url = format!(
-            "{}/{}/rustup-init{}",
-            RUSTUP_BASE_URL,
+            "{}/dist/{}/rustup-init{}",
+            workspace
+                .rustup_update_root()
+                .unwrap_or(DEFAULT_RUSTUP_UPDATE_ROOT),
             crate::HOST_TARGET,
             EXE_SUFFIX
         );
@@ -48,7 +67,7 @@ impl Tool for Rustup {
             .send()?
             .error_for_status()?;
 
-        let tempdir = tempdir()?;
+        let tempdir = tempfile::tempdir_in(workspace.temp_dir())?;
         let installer = &tempdir.path().join(format!("rustup-init{}", EXE_SUFFIX));
         {
             let mut file = File::create(installer)?;
@@ -58,7 +77,8 @@ impl Tool for Rustup {
 
         // TODO(rustup.rs#998): Remove `.no_output_timeout(true)` once rust-docs is no longer a
         // mandatory component.
-        Command::new(workspace, installer.to_string_lossy().as_ref())
+        let cmd = Command::new(workspace, installer.to_string_lossy().as_ref());
+        mirrors_env(workspace, cmd)
             .args(&[
                 "-y",
                 "--no-modify-path",
@@ -0,0 +1,4 @@
+fn main() {
+    let answer: u32 = "42";
+    println!("{}", answer);
+}
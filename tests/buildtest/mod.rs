@@ -23,6 +23,82 @@ fn test_hello_world() {
     });
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+fn test_sandbox_metrics() {
+    runner::run("hello-world", |run| {
+        run.build(SandboxBuilder::new().enable_networking(false), |build| {
+            let out = build.cargo().args(&["run"]).run_capture()?;
+            // The container's cgroup is not visible from inside another container.
+            if std::env::var("RUSTWIDE_TEST_INSIDE_DOCKER").is_err() {
+                assert!(out.metrics().cpu_time().is_some());
+                assert!(out.metrics().max_rss().unwrap() > 0);
+            }
+            Ok(())
+        })?;
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(unix)]
+fn test_sandbox_user() {
+    use std::os::unix::fs::MetadataExt;
+
+    runner::run("hello-world", |run| {
+        run.build(SandboxBuilder::new().enable_networking(false), |build| {
+            build.cargo().args(&["build"]).run()?;
+
+            // Files created by the build are owned by the current user by default.
+            let metadata = std::fs::metadata(build.host_target_dir().join("debug"))?;
+            assert_eq!(metadata.uid(), u32::from(nix::unistd::Uid::effective()));
+            assert_eq!(metadata.gid(), u32::from(nix::unistd::Gid::effective()));
+            Ok(())
+        })?;
+        Ok(())
+    });
+}
+
+#[test]
+fn test_cargo_messages() {
+    use rustwide::CargoMessage;
+
+    runner::run("hello-world", |run| {
+        run.build(SandboxBuilder::new().enable_networking(false), |build| {
+            let mut artifacts = Vec::new();
+            let mut finished = None;
+            build.cargo_messages(&["build"], |message| match message {
+                CargoMessage::CompilerArtifact(artifact) => {
+                    artifacts.push(artifact.target_name().to_string())
+                }
+                CargoMessage::BuildFinished(success) => finished = Some(success),
+                _ => {}
+            })?;
+
+            assert_eq!(artifacts, vec!["hello-world".to_string()]);
+            assert_eq!(finished, Some(true));
+            Ok(())
+        })?;
+        Ok(())
+    });
+}
+
+#[test]
+fn test_cargo_diagnostics() {
+    runner::run("compile-error", |run| {
+        run.build(SandboxBuilder::new().enable_networking(false), |build| {
+            let failure = build.cargo_diagnostics(&["build"]).unwrap_err();
+            let errors = failure.errors().collect::<Vec<_>>();
+            assert!(!errors.is_empty());
+            assert!(errors
+                .iter()
+                .any(|msg| msg.diagnostic().code() == Some("E0308")));
+            Ok(())
+        })?;
+        Ok(())
+    });
+}
+
 #[test]
 #[cfg(not(windows))]
 fn test_sandbox_oom() {
@@ -66,3 +142,72 @@ test_prepare_error!(
 );
 
 test_prepare_error!(test_yanked_deps, "yanked-deps", YankedDependencies);
+
+#[test]
+fn test_prepare_only() {
+    runner::run("hello-world", |run| run.prepare_only());
+
+    // The errors are the same ones returned when running the build.
+    runner::run("missing-cargotoml", |run| {
+        let res = run.prepare_only();
+        if let Some(rustwide::PrepareError::MissingCargoToml) =
+            res.err().and_then(|err| err.downcast().ok())
+        {
+            // Everything is OK!
+        } else {
+            panic!("didn't get the error MissingCargoToml");
+        }
+        Ok(())
+    });
+}
+
+#[test]
+fn test_require_outdated_lockfile() {
+    runner::run("outdated-lockfile", |run| {
+        let res = run.build_with(
+            SandboxBuilder::new().enable_networking(false),
+            |builder| builder.require_lockfile(true),
+            |_| Ok(()),
+        );
+        if let Some(rustwide::PrepareError::OutdatedLockfile) =
+            res.err().and_then(|err| err.downcast().ok())
+        {
+            // Everything is OK!
+        } else {
+            panic!("didn't get the error OutdatedLockfile");
+        }
+
+        // Without requiring it, the lockfile is regenerated.
+        run.build(SandboxBuilder::new().enable_networking(false), |build| {
+            build.cargo().args(&["run"]).run()?;
+            Ok(())
+        })?;
+        Ok(())
+    });
+}
+
+#[test]
+fn test_require_lockfile_with_added_dependency() {
+    use rustwide::DependencyKind;
+
+    runner::run("up-to-date-lockfile", |run| {
+        // Dependencies added to the build don't make the crate's lockfile outdated.
+        run.build_with(
+            SandboxBuilder::new().enable_networking(false),
+            |builder| {
+                builder.require_lockfile(true).add_dependency(
+                    "lazy_static",
+                    "1.0",
+                    DependencyKind::Normal,
+                )
+            },
+            |build| {
+                let lockfile = std::fs::read_to_string(build.host_source_dir().join("Cargo.lock"))?;
+                assert!(lockfile.contains("name = \"lazy_static\""));
+                build.cargo().args(&["run"]).run()?;
+                Ok(())
+            },
+        )?;
+        Ok(())
+    });
+}
@@ -1,5 +1,5 @@
 use failure::Error;
-use rustwide::{cmd::SandboxBuilder, Build, Crate, Toolchain, Workspace};
+use rustwide::{cmd::SandboxBuilder, Build, BuildBuilder, Crate, Toolchain, Workspace};
 use std::borrow::Cow;
 use std::path::Path;
 
@@ -44,10 +44,32 @@ impl Runner {
         &self,
         sandbox: SandboxBuilder,
         f: impl FnOnce(&Build) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.build_with(sandbox, |builder| builder, f)
+    }
+
+    pub(crate) fn build_with<T>(
+        &self,
+        sandbox: SandboxBuilder,
+        configure: impl FnOnce(BuildBuilder) -> BuildBuilder,
+        f: impl FnOnce(&Build) -> Result<T, Error>,
     ) -> Result<T, Error> {
         let mut dir = self.workspace.build_dir(&self.crate_name);
         dir.purge()?;
-        dir.build(self.toolchain, &self.krate, sandbox).run(f)
+        configure(dir.build(self.toolchain, &self.krate, sandbox)).run(f)
+    }
+
+    pub(crate) fn prepare_only(&self) -> Result<(), Error> {
+        let mut dir = self.workspace.build_dir(&self.crate_name);
+        dir.purge()?;
+        let res = dir
+            .build(self.toolchain, &self.krate, SandboxBuilder::new())
+            .prepare_only();
+        assert!(
+            !dir.host_source_dir().exists(),
+            "the source directory was not removed"
+        );
+        res
     }
 }
 
@@ -0,0 +1,33 @@
+use failure::Error;
+use rustwide::cmd::Command;
+
+#[test]
+#[cfg(unix)]
+fn test_workspace_env() -> Result<(), Error> {
+    let workspace = crate::utils::workspace_builder()
+        .env("RUSTWIDE_TEST_BASELINE", "baseline")
+        .env("RUSTWIDE_TEST_OVERRIDDEN", "baseline")
+        .env("RUSTWIDE_TEST_REMOVED", "baseline")
+        .init()?;
+
+    let out = Command::new(&workspace, "env")
+        .env("RUSTWIDE_TEST_OVERRIDDEN", "command")
+        .env_remove("RUSTWIDE_TEST_REMOVED")
+        .run_capture()?;
+    let lines = out.stdout_lines();
+    assert!(lines.iter().any(|l| l == "RUSTWIDE_TEST_BASELINE=baseline"));
+    assert!(lines
+        .iter()
+        .any(|l| l == "RUSTWIDE_TEST_OVERRIDDEN=command"));
+    assert!(!lines
+        .iter()
+        .any(|l| l.starts_with("RUSTWIDE_TEST_REMOVED=")));
+
+    let out = Command::new(&workspace, "env").env_clear().run_capture()?;
+    assert!(!out
+        .stdout_lines()
+        .iter()
+        .any(|l| l.starts_with("RUSTWIDE_TEST_BASELINE=")));
+
+    Ok(())
+}
@@ -1,6 +1,9 @@
 use failure::Error;
+use log::LevelFilter;
 use rustwide::cmd::{Command, CommandError, SandboxBuilder};
+use rustwide::logging::{self, LogStorage};
 use rustwide::{Crate, PrepareError, Toolchain, Workspace};
+use std::path::Path;
 
 #[test]
 fn test_fetch() -> Result<(), Error> {
@@ -12,7 +15,9 @@ fn test_fetch() -> Result<(), Error> {
 
     let mut repo = Repo::new(&workspace)?;
     let krate = Crate::git(&repo.serve()?);
+    assert!(!krate.is_cached(&workspace));
     krate.fetch(&workspace)?;
+    assert!(krate.is_cached(&workspace));
 
     // Return the commit that was used during a build.
     let cloned_commit = || -> Result<String, Error> {
@@ -50,6 +55,53 @@ fn test_fetch() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn test_fetch_tag() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let mut repo = Repo::new(&workspace)?;
+    let tagged_commit = repo.last_commit_sha.clone().unwrap();
+    repo.tag(&workspace, "v1")?;
+    repo.commit(&workspace)?;
+    assert_ne!(tagged_commit, repo.last_commit_sha.clone().unwrap());
+
+    let krate = Crate::git(&repo.serve()?).tag("v1");
+    krate.fetch(&workspace)?;
+    assert_eq!(tagged_commit, krate.git_commit(&workspace).unwrap());
+
+    Ok(())
+}
+
+#[test]
+fn test_purge_from_cache() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let repo = Repo::new(&workspace)?;
+    let krate = Crate::git(&repo.serve()?);
+    krate.fetch(&workspace)?;
+    assert!(krate.is_cached(&workspace));
+
+    krate.purge_from_cache(&workspace)?;
+    assert!(!krate.is_cached(&workspace));
+    assert!(krate.git_commit(&workspace).is_none());
+
+    // Purging a crate that isn't cached does nothing.
+    krate.purge_from_cache(&workspace)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_local_git_commit() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let repo = Repo::new(&workspace)?;
+    let krate = Crate::local(repo.source.path());
+    assert_eq!(repo.last_commit_sha, krate.git_commit(&workspace));
+
+    Ok(())
+}
+
 #[test]
 fn test_fetch_with_authentication() -> Result<(), Error> {
     let workspace = crate::utils::init_workspace()?;
@@ -69,6 +121,158 @@ fn test_fetch_with_authentication() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn test_credentials_not_logged() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let repo = Repo::new(&workspace)?.authenticated();
+    let url = repo
+        .serve()?
+        .replacen("http://", "http://rustwide:hunter2@", 1);
+    let krate = Crate::git(&url);
+
+    let storage = LogStorage::new(LevelFilter::Trace);
+    logging::capture(&storage, || {
+        // The test server rejects all the credentials, so the fetch fails.
+        assert!(krate.fetch(&workspace).is_err());
+    });
+    assert!(!storage.to_string().is_empty());
+    assert!(!storage.to_string().contains("hunter2"));
+
+    Ok(())
+}
+
+#[test]
+fn test_fetch_depth() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let mut repo = Repo::new(&workspace)?;
+    repo.commit(&workspace)?;
+    repo.commit(&workspace)?;
+
+    // The dumb HTTP protocol used by the test server doesn't support shallow clones.
+    let krate = Crate::git(&repo.file_url()).depth(Some(1));
+    krate.fetch(&workspace)?;
+    assert_eq!(repo.last_commit_sha, krate.git_commit(&workspace));
+    let count_commits = |krate: &Crate| {
+        in_source_dir(&workspace, krate, "test_fetch_depth", |source| {
+            Ok(Command::new(&workspace, "git")
+                .args(&["rev-list", "--count", "HEAD"])
+                .cd(source)
+                .run_capture()?
+                .stdout_lines()[0]
+                .to_string())
+        })
+    };
+    assert_eq!("1", count_commits(&krate)?);
+
+    // Fetching the repository again without a depth fetches its whole history.
+    let krate = Crate::git(&repo.file_url());
+    krate.fetch(&workspace)?;
+    assert_eq!(repo.last_commit_sha, krate.git_commit(&workspace));
+    assert_eq!("3", count_commits(&krate)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_fetch_submodules() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let submodule = Repo::new(&workspace)?;
+    let mut repo = Repo::new(&workspace)?;
+    repo.add_submodule(&workspace, &submodule.serve()?, "vendor")?;
+    let url = repo.serve()?;
+
+    let has_submodule = |krate: &Crate| {
+        in_source_dir(&workspace, krate, "test_fetch_submodules", |source| {
+            Ok(source.join("vendor").join("Cargo.toml").is_file())
+        })
+    };
+
+    let krate = Crate::git(&url);
+    krate.fetch(&workspace)?;
+    assert!(!has_submodule(&krate)?);
+
+    let krate = Crate::git(&url).submodules(true);
+    krate.fetch(&workspace)?;
+    assert!(has_submodule(&krate)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_path_in_repo() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+    let toolchain = Toolchain::Dist {
+        name: "stable".into(),
+    };
+    toolchain.install(&workspace)?;
+
+    let mut repo = Repo::new(&workspace)?;
+    Command::new(&workspace, "cargo")
+        .args(&["init", "--name", "bar", "--lib"])
+        .args(&[repo.source.path().join("bar")])
+        .run()?;
+    repo.commit(&workspace)?;
+
+    let krate = Crate::git(&repo.serve()?).path_in_repo("bar");
+    krate.fetch(&workspace)?;
+
+    let mut dir = workspace.build_dir("integration-crates_git-test_path_in_repo");
+    dir.purge()?;
+    // Seed the source directory, to check the subdirectory is merged into it.
+    let source = dir.host_source_dir();
+    std::fs::create_dir_all(source.join("src"))?;
+    std::fs::write(source.join("seeded.txt"), b"seeded")?;
+    std::fs::write(source.join("src").join("main.rs"), b"fn main() {}")?;
+
+    dir.build(&toolchain, &krate, SandboxBuilder::new())
+        .keep_existing_source(true)
+        .run(|build| {
+            let source = build.host_source_dir();
+            let manifest = std::fs::read_to_string(source.join("Cargo.toml"))?;
+            assert!(manifest.contains("name = \"bar\""));
+            assert!(source.join("src").join("lib.rs").is_file());
+            // Files outside of the subdirectory are not copied.
+            assert!(!source.join("bar").exists());
+            assert!(!source.join(".git").exists());
+            // Files seeded in the source directory are kept.
+            assert_eq!(std::fs::read(source.join("seeded.txt"))?, b"seeded");
+            assert!(source.join("src").join("main.rs").is_file());
+            Ok(())
+        })?;
+
+    // Subdirectories missing from the repository are rejected.
+    let missing = Crate::git(&repo.serve()?).path_in_repo("missing");
+    missing.fetch(&workspace)?;
+    dir.purge()?;
+    let res = dir
+        .build(&toolchain, &missing, SandboxBuilder::new())
+        .run(|_| Ok(()));
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+/// Build the crate and run `f` with the path of its source directory.
+fn in_source_dir<T>(
+    workspace: &Workspace,
+    krate: &Crate,
+    name: &str,
+    f: impl FnOnce(&Path) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let toolchain = Toolchain::Dist {
+        name: "stable".into(),
+    };
+    toolchain.install(workspace)?;
+
+    let mut dir = workspace.build_dir(&format!("integration-crates_git-{}", name));
+    dir.purge()?;
+    dir.build(&toolchain, krate, SandboxBuilder::new())
+        .run(|build| f(&build.host_source_dir()))
+}
+
 struct Repo {
     source: tempfile::TempDir,
     last_commit_sha: Option<String>,
@@ -128,6 +332,30 @@ impl Repo {
         Ok(())
     }
 
+    fn add_submodule(&mut self, workspace: &Workspace, url: &str, path: &str) -> Result<(), Error> {
+        Command::new(workspace, "git")
+            .args(&["submodule", "add", url, path])
+            .cd(self.source.path())
+            .run()?;
+        self.commit(workspace)
+    }
+
+    fn tag(&self, workspace: &Workspace, name: &str) -> Result<(), Error> {
+        Command::new(workspace, "git")
+            .args(&["tag", name])
+            .cd(self.source.path())
+            .run()?;
+        Command::new(workspace, "git")
+            .args(&["update-server-info"])
+            .cd(self.source.path())
+            .run()?;
+        Ok(())
+    }
+
+    fn file_url(&self) -> String {
+        format!("file://{}", self.source.path().display())
+    }
+
     fn serve(&self) -> Result<String, Error> {
         let server =
             tiny_http::Server::http("localhost:0").map_err(|e| failure::err_msg(e.to_string()))?;
@@ -1 +1,4 @@
+mod cmd;
+mod crates_download_info;
 mod crates_git;
+mod workspace;
@@ -0,0 +1,42 @@
+use failure::Error;
+use rustwide::{Crate, FetchReport};
+use std::path::Path;
+
+#[test]
+fn test_fetch_all() -> Result<(), Error> {
+    let workspace = crate::utils::init_workspace()?;
+
+    let local = || {
+        Crate::local(
+            &Path::new("tests")
+                .join("buildtest")
+                .join("crates")
+                .join("hello-world"),
+        )
+    };
+    let crates = vec![
+        local(),
+        // Invalid crate names are rejected before reaching out to the network.
+        Crate::crates_io("../invalid", "1.0.0"),
+        local(),
+        Crate::crates_io("invalid name", "1.0.0"),
+        local(),
+    ];
+
+    for concurrency in &[0, 1, 2, 10] {
+        let results = workspace.fetch_all(&crates, *concurrency);
+        // The results are in the same order as the crates, regardless of the concurrency.
+        assert_eq!(
+            results.iter().map(|res| res.is_ok()).collect::<Vec<_>>(),
+            vec![true, false, true, false, true]
+        );
+
+        let report = FetchReport::new(&results);
+        assert_eq!(report.succeeded(), 3);
+        assert_eq!(report.other_errors(), 2);
+    }
+
+    assert!(workspace.fetch_all(&[], 4).is_empty());
+
+    Ok(())
+}
@@ -6,6 +6,10 @@ use std::path::Path;
 static USER_AGENT: &str = "rustwide-tests (https://github.com/rust-lang/rustwide)";
 
 pub(crate) fn init_workspace() -> Result<Workspace, Error> {
+    Ok(workspace_builder().init()?)
+}
+
+pub(crate) fn workspace_builder() -> WorkspaceBuilder {
     init_logs();
     let workspace_path = Path::new(".workspaces").join("integration");
     let mut builder = WorkspaceBuilder::new(&workspace_path, USER_AGENT).fast_init(true);
@@ -14,7 +18,7 @@ pub(crate) fn init_workspace() -> Result<Workspace, Error> {
         builder = builder.running_inside_docker(true);
     }
 
-    Ok(builder.init()?)
+    builder
 }
 
 fn init_logs() {